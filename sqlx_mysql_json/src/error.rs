@@ -1,22 +1,194 @@
+use sqlx::mysql::MySqlDatabaseError;
 use std::fmt;
 
-//#[derive(Debug)]
+#[derive(Debug)]
 pub enum Error {
     Parameter(String),
     TupleType(String),
     Decode(String),
     Sqlx(String),
+    /// the connection to mysql broke or none could be had, nothing wrong with the query
+    Connection(String),
+    /// `error` came from turning the parameter at `index` into a sql value
+    AtParameter {
+        index: usize,
+        error: Box<Error>,
+    },
+    /// an error returned by mysql itself
+    Database {
+        number: u16,
+        sqlstate: Option<String>,
+        message: String,
+    },
     SerdeJson(String),
 }
 
+impl Error {
+    /// what went wrong, one of "parameter", "tuple", "decode", "sql", "json"
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Parameter(_) => "parameter",
+            Error::TupleType(_) => "tuple",
+            Error::Decode(_) => "decode",
+            Error::Sqlx(_) => "sql",
+            Error::Connection(_) => "connection",
+            Error::AtParameter { error, .. } => error.kind(),
+            Error::Database { .. } => "sql",
+            Error::SerdeJson(_) => "json",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Error::Parameter(s) => s,
+            Error::TupleType(s) => s,
+            Error::Decode(s) => s,
+            Error::Sqlx(s) => s,
+            Error::Connection(s) => s,
+            Error::AtParameter { error, .. } => error.message(),
+            Error::Database { message, .. } => message,
+            Error::SerdeJson(s) => s,
+        }
+    }
+
+    /// index into the query json "parameters" of the one that could not be used
+    pub fn parameter_index(&self) -> Option<usize> {
+        match self {
+            Error::AtParameter { index, .. } => Some(*index),
+            _ => None,
+        }
+    }
+
+    /// who is to blame, see `Class`
+    pub fn class(&self) -> Class {
+        match self {
+            Error::Parameter(_) | Error::TupleType(_) | Error::SerdeJson(_) => Class::Client,
+            Error::AtParameter { error, .. } => error.class(),
+            Error::Decode(_) => Class::Internal,
+            Error::Sqlx(_) => Class::Client,
+            Error::Connection(_) => Class::Unavailable,
+            Error::Database { number, .. } => match number {
+                //duplicate entry, foreign key and check constraint violations
+                1022 | 1062 | 1169 | 1216 | 1217 | 1451 | 1452 | 1557 | 1586 | 3819 => {
+                    Class::Conflict
+                }
+                //deadlock found, lock wait timeout exceeded
+                1205 | 1213 => Class::Retryable,
+                //too many connections, server shutdown, server gone away, lost connection
+                1040 | 1053 | 2006 | 2013 => Class::Unavailable,
+                _ => Class::Client,
+            },
+        }
+    }
+
+    /// the same query might succeed if sent again a bit later
+    pub fn retryable(&self) -> bool {
+        matches!(self.class(), Class::Retryable | Class::Unavailable)
+    }
+
+    /// the mysql error number, eg 1062 for duplicate entry
+    pub fn number(&self) -> Option<u16> {
+        match self {
+            Error::Database { number, .. } => Some(*number),
+            _ => None,
+        }
+    }
+
+    pub fn sqlstate(&self) -> Option<&str> {
+        match self {
+            Error::Database { sqlstate, .. } => sqlstate.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// `{ "kind": "sql", "message": "...", "code": 1062, "sqlstate": "23000", "parameterIndex": null, "retryable": false }`,
+    /// code and sqlstate are null unless mysql returned the error, parameterIndex unless a parameter was bad
+    pub fn to_value(&self) -> serde_json::Value {
+        serde_json::json!({
+            "kind": self.kind(),
+            "message": self.message(),
+            "code": self.number(),
+            "sqlstate": self.sqlstate(),
+            "parameterIndex": self.parameter_index(),
+            "retryable": self.retryable(),
+        })
+    }
+}
+
+/// how an error should be reported, eg as http status
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+    /// bad sql or parameters, sending it again wont help
+    Client,
+    /// the query broke a unique, foreign key or check constraint
+    Conflict,
+    /// deadlock or lock wait timeout, the transaction was rolled back and can be retried
+    Retryable,
+    /// mysql could not be reached or the connection broke
+    Unavailable,
+    /// a result could not be converted to json, a bug here rather than in the query
+    Internal,
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        let mysql_error = err
+            .as_database_error()
+            .and_then(|db_err| db_err.try_downcast_ref::<MySqlDatabaseError>());
+        if let Some(mysql_error) = mysql_error {
+            return Error::Database {
+                number: mysql_error.number(),
+                sqlstate: mysql_error.code().map(|code| code.to_string()),
+                message: mysql_error.message().to_string(),
+            };
+        }
+        match err {
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::Protocol(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => Error::Connection(err.to_string()),
+            sqlx::Error::ColumnDecode { .. } | sqlx::Error::Decode(_) => {
+                Error::Decode(err.to_string())
+            }
+            _ => Error::Sqlx(err.to_string()),
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Error::Parameter(s) => write!(f, "{:?}", s),
-            Error::TupleType(s) => write!(f, "{:?}", s),
-            Error::Decode(s) => write!(f, "{:?}", s),
-            Error::Sqlx(s) => write!(f, "{:?}", s),
-            Error::SerdeJson(s) => write!(f, "{:?}", s),
+        match self.parameter_index() {
+            Some(index) => write!(f, "parameter {}: {}", index, self.message()),
+            None => write!(f, "{}", self.message()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mysql(number: u16) -> Error {
+        Error::Database {
+            number,
+            sqlstate: None,
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn errors_are_classified() {
+        assert_eq!(mysql(1062).class(), Class::Conflict);
+        assert_eq!(mysql(1213).class(), Class::Retryable);
+        assert_eq!(mysql(1064).class(), Class::Client);
+        assert!(mysql(1205).retryable());
+        assert!(!mysql(1452).retryable());
+        assert_eq!(
+            Error::from(sqlx::Error::PoolTimedOut).class(),
+            Class::Unavailable
+        );
+        assert_eq!(Error::Decode(String::new()).class(), Class::Internal);
+    }
+}
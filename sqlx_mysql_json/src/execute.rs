@@ -1,7 +1,8 @@
+use futures::stream::BoxStream;
 use sqlx::{
-    mysql::{MySqlQueryResult, MySqlRow},
-    pool::PoolConnection,
-    MySql, MySqlPool,
+    mysql::{MySqlArguments, MySqlQueryResult, MySqlRow},
+    query::Query as SqlxQuery,
+    Executor, MySql,
 };
 
 use crate::parse::Query;
@@ -14,13 +15,10 @@ pub enum Parameter {
     Str(String),
     Bool(bool),
     Bytes(Vec<u8>),
-    NULL,
+    Null,
 }
 
-pub async fn fetch_all(
-    pool: &MySqlPool,
-    query: &Query,
-) -> Result<Vec<MySqlRow>, sqlx::error::Error> {
+fn bind(query: &Query) -> SqlxQuery<'_, MySql, MySqlArguments> {
     let mut q = sqlx::query(&query.sql);
     for p in query.parameters.iter() {
         match p {
@@ -30,49 +28,28 @@ pub async fn fetch_all(
             Parameter::Str(x) => q = q.bind(x),
             Parameter::Bool(x) => q = q.bind(x),
             Parameter::Bytes(x) => q = q.bind(x),
-            Parameter::NULL => q = q.bind(None::<String>),
+            Parameter::Null => q = q.bind(None::<String>),
         }
     }
-    let rows = q.fetch_all(pool).await?;
-    Ok(rows)
+    q
 }
 
-pub async fn execute(
-    pool: &MySqlPool,
-    query: &Query,
-) -> Result<MySqlQueryResult, sqlx::error::Error> {
-    let mut q = sqlx::query(&query.sql);
-    for p in query.parameters.iter() {
-        match p {
-            Parameter::Int(x) => q = q.bind(x),
-            Parameter::Uint(x) => q = q.bind(x),
-            Parameter::Float(x) => q = q.bind(x),
-            Parameter::Str(x) => q = q.bind(x),
-            Parameter::Bool(x) => q = q.bind(x),
-            Parameter::Bytes(x) => q = q.bind(x),
-            Parameter::NULL => q = q.bind(None::<String>),
-        }
-    }
-    let result = q.execute(pool).await?;
-    Ok(result)
+/// rows one at a time as mysql sends them, `executor` is a `&MySqlPool` or a `&mut MySqlConnection`
+pub fn fetch<'q, 'c: 'q, E>(
+    executor: E,
+    query: &'q Query,
+) -> BoxStream<'q, Result<MySqlRow, sqlx::Error>>
+where
+    E: 'q + Executor<'c, Database = MySql>,
+{
+    bind(query).fetch(executor)
 }
 
-pub async fn execute_in_connection(
-    conn: &mut PoolConnection<MySql>,
-    query: &Query,
-) -> Result<MySqlQueryResult, sqlx::error::Error> {
-    let mut q = sqlx::query(&query.sql);
-    for p in query.parameters.iter() {
-        match p {
-            Parameter::Int(x) => q = q.bind(x),
-            Parameter::Uint(x) => q = q.bind(x),
-            Parameter::Float(x) => q = q.bind(x),
-            Parameter::Str(x) => q = q.bind(x),
-            Parameter::Bool(x) => q = q.bind(x),
-            Parameter::Bytes(x) => q = q.bind(x),
-            Parameter::NULL => q = q.bind(None::<String>),
-        }
-    }
-    let result = q.execute(&mut **conn).await?;
+/// `executor` is a `&MySqlPool` or a `&mut MySqlConnection`
+pub async fn execute<'e, E>(executor: E, query: &Query) -> Result<MySqlQueryResult, sqlx::Error>
+where
+    E: Executor<'e, Database = MySql>,
+{
+    let result = bind(query).execute(executor).await?;
     Ok(result)
 }
@@ -1,11 +1,13 @@
 use error::Error;
-use sqlx::{pool::PoolConnection, MySql, MySqlPool};
+use futures::{Future, TryStreamExt};
+use sqlx::{pool::PoolConnection, Acquire, Executor, MySql, MySqlConnection};
 
 mod base64;
 pub mod error;
 mod execute;
 mod parse;
 pub mod row;
+#[allow(dead_code)] //copy-pasted, only geom_to_wkb and wkb_to_geom are used
 mod wkb;
 
 /// calls `fetch_all()` for SELECT queries, otherwise calls `execute()`
@@ -32,33 +34,172 @@ mod wkb;
 ///   * This is always defined and is empty if the query returned no rows.
 ///   */
 ///  rows: [];
+///  /**
+///   * Only for SELECT queries with `"columns": true` in the query json,
+///   * also defined when no rows matched.
+///   */
+///  columns?: { name: string; ordinal: number; type: string; nullable: boolean | null; unsigned: boolean }[];
 ///}
 /// ```
-pub async fn query(pool: &MySqlPool, s: &String) -> Result<serde_json::Value, Error> {
-    let query = parse::string_to_query(s)?;
+///
+/// `executor` is a `&MySqlPool` or a `&mut MySqlConnection`
+pub async fn query<'e, E>(executor: E, s: &str) -> Result<serde_json::Value, Error>
+where
+    E: Acquire<'e, Database = MySql>,
+{
+    query_with_results(executor, s, &[], Limits::default()).await
+}
+
+/// same as `query()` but stops reading rows at `limits`, see `Limits`
+pub async fn query_limited<'e, E>(
+    executor: E,
+    s: &str,
+    limits: Limits,
+) -> Result<serde_json::Value, Error>
+where
+    E: Acquire<'e, Database = MySql>,
+{
+    query_with_results(executor, s, &[], limits).await
+}
+
+/// same as `query_limited()` but parameters can refer to the results of earlier statements,
+/// `["Ref", 0, "insertId"]` is the insertId of `results[0]` and
+/// `["Ref", 0, "id"]` is the "id" column of the first row in `results[0]`,
+/// `["Ref", [2, 0], "insertId"]` is the insertId of the first statement in the optional block `results[2]`
+pub async fn query_with_results<'e, E>(
+    executor: E,
+    s: &str,
+    results: &[serde_json::Value],
+    limits: Limits,
+) -> Result<serde_json::Value, Error>
+where
+    E: Acquire<'e, Database = MySql>,
+{
+    let query = parse::string_to_query_with_results(s, results)?;
+    let mut conn = executor.acquire().await?;
     match is_select_query(&query.sql) {
-        true => fetch_all(pool, s).await,
-        false => execute(pool, s).await,
+        true => fetch_all_parsed(&mut conn, &query, limits).await,
+        false => execute_parsed(&mut *conn, &query).await,
+    }
+}
+
+/// caps on how much of a SELECT result is returned, `None` means no cap
+///
+/// rows past a cap are discarded as mysql sends them rather than kept, the result gets `"truncated": true` instead
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    pub max_rows: Option<u64>,
+    /// of the rows serialized as json
+    pub max_bytes: Option<usize>,
+}
+
+impl Limits {
+    /// the lower of both caps
+    pub fn min(self, other: Limits) -> Limits {
+        fn min<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+        Limits {
+            max_rows: min(self.max_rows, other.max_rows),
+            max_bytes: min(self.max_bytes, other.max_bytes),
+        }
+    }
+
+    /// true if one more row of `row_bytes` would go past a cap,
+    /// given `num_rows` rows of `num_bytes` so far
+    pub fn exceeded_by(&self, num_rows: u64, num_bytes: usize, row_bytes: usize) -> bool {
+        self.max_rows.is_some_and(|max| num_rows >= max)
+            || self
+                .max_bytes
+                .is_some_and(|max| num_bytes + row_bytes > max)
     }
 }
 
-pub async fn fetch_all(pool: &MySqlPool, s: &String) -> Result<serde_json::Value, Error> {
+/// `executor` is a `&MySqlPool` or a `&mut MySqlConnection`
+pub async fn fetch_all<'e, E>(executor: E, s: &str) -> Result<serde_json::Value, Error>
+where
+    E: Acquire<'e, Database = MySql>,
+{
     let query = parse::string_to_query(s)?;
-    match execute::fetch_all(pool, &query).await {
-        Err(err) => Err(Error::Sqlx(err.to_string())),
-        Ok(rows) => {
-            let value = serde_json::json!({
-                "rows": row::rows_to_value(&rows)?
-            });
-            Ok(value)
+    let mut conn = executor.acquire().await?;
+    fetch_all_parsed(&mut conn, &query, Limits::default()).await
+}
+
+/// `"columns"` comes from `describe()` rather than the rows, so it is there even if no rows matched
+async fn fetch_all_parsed(
+    conn: &mut MySqlConnection,
+    query: &parse::Query,
+    limits: Limits,
+) -> Result<serde_json::Value, Error> {
+    let mut value = match query.columns {
+        false => serde_json::json!({}),
+        true => {
+            let describe = conn.describe(&query.sql).await?;
+            serde_json::json!({ "columns": row::describe_to_value(&describe) })
+        }
+    };
+    let mut rows = vec![];
+    let mut num_bytes = 0;
+    let mut stream = execute::fetch(&mut *conn, query);
+    while let Some(row) = stream.try_next().await? {
+        let row_value = row::row_to_value(&row)?;
+        //only serialize twice if there is a byte limit
+        let row_bytes = match limits.max_bytes {
+            None => 0,
+            Some(_) => serde_json::to_vec(&row_value).map_or(0, |bytes| bytes.len()),
+        };
+        if limits.exceeded_by(rows.len() as u64, num_bytes, row_bytes) {
+            value["truncated"] = true.into();
+            break;
+        }
+        num_bytes += row_bytes;
+        rows.push(row_value);
+    }
+    value["rows"] = rows.into();
+    Ok(value)
+}
+
+/// like `fetch_all()` but instead of collecting every row, `on_row` is called with each row
+/// as soon as mysql sends it, so big results dont have to fit in memory
+///
+/// `on_row` returning false stops early, eg when the receiving end went away
+pub async fn fetch_each<'e, E, F, Fut>(executor: E, s: &str, mut on_row: F) -> Result<(), Error>
+where
+    E: Executor<'e, Database = MySql>,
+    F: FnMut(serde_json::Value) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let query = parse::string_to_query(s)?;
+    let mut rows = execute::fetch(executor, &query);
+    while let Some(row) = rows.try_next().await? {
+        if !on_row(row::row_to_value(&row)?).await {
+            break;
         }
     }
+    Ok(())
 }
 
-pub async fn execute(pool: &MySqlPool, s: &String) -> Result<serde_json::Value, Error> {
+/// `executor` is a `&MySqlPool` or a `&mut MySqlConnection`
+pub async fn execute<'e, E>(executor: E, s: &str) -> Result<serde_json::Value, Error>
+where
+    E: Executor<'e, Database = MySql>,
+{
     let query = parse::string_to_query(s)?;
-    match execute::execute(pool, &query).await {
-        Err(err) => Err(Error::Sqlx(err.to_string())),
+    execute_parsed(executor, &query).await
+}
+
+async fn execute_parsed<'e, E>(
+    executor: E,
+    query: &parse::Query,
+) -> Result<serde_json::Value, Error>
+where
+    E: Executor<'e, Database = MySql>,
+{
+    match execute::execute(executor, query).await {
+        Err(err) => Err(err.into()),
         Ok(result) => {
             let num_affected_rows = result.rows_affected().to_string();
             let insert_id = result.last_insert_id().to_string();
@@ -76,29 +217,145 @@ pub async fn execute(pool: &MySqlPool, s: &String) -> Result<serde_json::Value,
 }
 
 pub async fn execute_in_connection(
-    pool: &mut PoolConnection<MySql>,
-    s: &String,
+    conn: &mut PoolConnection<MySql>,
+    s: &str,
 ) -> Result<serde_json::Value, Error> {
-    let query = parse::string_to_query(s)?;
-    match execute::execute_in_connection(pool, &query).await {
-        Err(err) => Err(Error::Sqlx(err.to_string())),
-        Ok(result) => {
-            let num_affected_rows = result.rows_affected().to_string();
-            let insert_id = result.last_insert_id().to_string();
+    execute(&mut **conn, s).await
+}
 
-            let value = serde_json::json!({
-                "numAffectedRows": ["BigInt", num_affected_rows],
-                "numChangedRows": ["BigInt",num_affected_rows],
-                "insertId": ["BigInt", insert_id],
-                "rows": []
-            });
+/// true if `s` is a query json string with a SELECT query,
+/// its parameters are not looked at so `["Ref", index, key]` parameters are fine
+pub fn is_select(s: &str) -> bool {
+    match parse::string_to_sql(s) {
+        Ok(sql) => is_select_query(&sql),
+        Err(_) => false,
+    }
+}
 
-            Ok(value)
+/// true if `s` is a query json string whose sql ends the current transaction,
+/// DDL, LOCK TABLES, COMMIT, SET autocommit and the like (see "Statements That Cause an Implicit Commit" in the mysql docs)
+///
+/// comments are ignored, except the content of `/*! ... */` which mysql runs
+pub fn ends_transaction(s: &str) -> bool {
+    let Ok(sql) = parse::string_to_sql(s) else {
+        return false;
+    };
+    let sql = without_comments(&sql.to_lowercase());
+    let sql = sql.trim().trim_end_matches(';');
+    let mut words = sql
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty());
+    match words.next() {
+        None => false,
+        Some("rollback") => !matches!(
+            (words.next(), words.next()),
+            (Some("to"), _) | (Some("work"), Some("to"))
+        ),
+        Some("set") => words.next() == Some("password") || sets_autocommit(sql),
+        Some("load") => words.next() == Some("index"),
+        Some("reset") => words.next() != Some("persist"),
+        Some(word) => matches!(
+            word,
+            "alter"
+                | "create"
+                | "drop"
+                | "rename"
+                | "truncate"
+                | "grant"
+                | "revoke"
+                | "lock"
+                | "unlock"
+                | "begin"
+                | "start"
+                | "stop"
+                | "commit"
+                | "analyze"
+                | "cache"
+                | "check"
+                | "optimize"
+                | "repair"
+                | "flush"
+                | "import"
+                | "change"
+                | "install"
+                | "uninstall"
+                | "xa"
+        ),
+    }
+}
+
+/// true if one of the assignments of a SET statement is to autocommit,
+/// `SET autocommit = 1`, `SET SESSION autocommit = 1`, `SET @a = 1, @@autocommit := 1` etc
+fn sets_autocommit(sql: &str) -> bool {
+    sql.split(',')
+        .filter_map(|assignment| assignment.split_once('='))
+        .any(|(target, _)| target.contains("autocommit"))
+}
+
+/// `sql` with comments replaced by a space, quoted strings are left alone
+/// and `/*!50000 ... */` is replaced by its content like mysql does
+fn without_comments(sql: &str) -> String {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut out = String::with_capacity(sql.len());
+    let mut quote = None;
+    let mut executable = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        i += 1;
+        if let Some(q) = quote {
+            out.push(c);
+            if c == '\\' {
+                out.extend(next);
+                i += 1;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match (c, next) {
+            ('\'' | '"' | '`', _) => {
+                quote = Some(c);
+                out.push(c);
+            }
+            ('/', Some('*')) if chars.get(i + 1) == Some(&'!') => {
+                i += 2;
+                while chars.get(i).is_some_and(|c| c.is_ascii_digit()) {
+                    i += 1;
+                }
+                executable += 1;
+                out.push(' ');
+            }
+            ('*', Some('/')) if executable > 0 => {
+                i += 1;
+                executable -= 1;
+                out.push(' ');
+            }
+            ('/', Some('*')) => {
+                i += 1;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    i += 1;
+                }
+                i += 2;
+                out.push(' ');
+            }
+            //`--` only starts a comment if followed by whitespace
+            ('#', _) | ('-', Some('-'))
+                if c == '#' || chars.get(i + 1).copied().unwrap_or(' ').is_whitespace() =>
+            {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                out.push(' ');
+            }
+            _ => out.push(c),
         }
     }
+    out
 }
 
-fn is_select_query(sql: &String) -> bool {
+fn is_select_query(sql: &str) -> bool {
     let first_word = sql.split_whitespace().next();
     match first_word {
         None => false,
@@ -119,4 +376,57 @@ mod tests {
         let result = add(2, 2);
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn selects_with_refs_are_selects() {
+        let s = r#"{"sql": "SELECT * FROM Child WHERE parentId = ?", "parameters": [["Ref", 0, "insertId"]]}"#;
+        assert!(is_select(s));
+        let s = r#"{"sql": "DELETE FROM Child WHERE parentId = ?", "parameters": [["Ref", 0, "insertId"]]}"#;
+        assert!(!is_select(s));
+        assert!(!is_select(r#"{"parameters": []}"#));
+    }
+
+    #[test]
+    fn statements_that_end_a_transaction() {
+        let q = |sql: &str| serde_json::json!({ "sql": sql, "parameters": [] }).to_string();
+        assert!(ends_transaction(&q("ALTER TABLE Post ADD COLUMN x INT")));
+        assert!(ends_transaction(&q("commit")));
+        assert!(ends_transaction(&q("SET autocommit = 1")));
+        assert!(ends_transaction(&q("COMMIT;")));
+        assert!(ends_transaction(&q("SET SESSION autocommit = 1")));
+        assert!(ends_transaction(&q("SET @@autocommit=1")));
+        assert!(ends_transaction(&q(
+            "SET @a = 1, @@session.autocommit := 1"
+        )));
+        assert!(ends_transaction(&q("/* x */ COMMIT")));
+        assert!(ends_transaction(&q("-- x\nCOMMIT")));
+        assert!(ends_transaction(&q("# x\n  commit work")));
+        assert!(ends_transaction(&q("/*!40101 SET autocommit=1 */")));
+        assert!(ends_transaction(&q("SET PASSWORD = 'secret'")));
+        assert!(ends_transaction(&q("ROLLBACK")));
+        assert!(!ends_transaction(&q("ROLLBACK WORK TO SAVEPOINT a")));
+        assert!(!ends_transaction(&q("SET @a = 'autocommit'")));
+        assert!(!ends_transaction(&q("SELECT 1 /* COMMIT */")));
+        assert!(!ends_transaction(&q("/* COMMIT */ SELECT '-- x\nCOMMIT'")));
+        assert!(!ends_transaction(&q("ROLLBACK TO SAVEPOINT a")));
+        assert!(!ends_transaction(&q("UPDATE Post SET title = 'create'")));
+        assert!(!ends_transaction(&q("SET @a = 1")));
+    }
+
+    #[test]
+    fn the_lower_limit_wins() {
+        let database = Limits {
+            max_rows: Some(10_000),
+            max_bytes: None,
+        };
+        let request = Limits {
+            max_rows: Some(100),
+            max_bytes: Some(1024),
+        };
+        assert_eq!(database.min(request), request);
+        assert_eq!(database.min(Limits::default()), database);
+        assert!(!request.exceeded_by(99, 1000, 24));
+        assert!(request.exceeded_by(100, 0, 0));
+        assert!(request.exceeded_by(0, 1000, 25));
+    }
 }
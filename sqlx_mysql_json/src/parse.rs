@@ -8,41 +8,73 @@ use crate::{base64::base64string_to_vecu8, error::Error, execute::Parameter};
 pub struct JsonQuery {
     sql: String,
     parameters: Vec<Value>,
+    #[serde(default)]
+    columns: bool,
 }
 
 #[derive(Debug)]
 pub struct Query {
     pub sql: String,
     pub parameters: Vec<Parameter>,
+    /// include column metadata with SELECT results
+    pub columns: bool,
 }
 
-pub fn string_to_query(string: &String) -> Result<Query, Error> {
+/// only the sql of a query json string
+#[derive(Deserialize)]
+struct JsonSql {
+    sql: String,
+}
+
+/// the sql of query json `string` without converting its parameters,
+/// for looking at a statement before the results its `["Ref", index, key]` parameters need exist
+pub fn string_to_sql(string: &str) -> Result<String, Error> {
+    match serde_json::from_str::<JsonSql>(string) {
+        Err(err) => Err(Error::SerdeJson(err.to_string())),
+        Ok(query) => Ok(query.sql),
+    }
+}
+
+pub fn string_to_query(string: &str) -> Result<Query, Error> {
+    string_to_query_with_results(string, &[])
+}
+
+/// same as `string_to_query` but `["Ref", index, key]` parameters are taken from `results`
+pub fn string_to_query_with_results(string: &str, results: &[Value]) -> Result<Query, Error> {
     match serde_json::from_str::<JsonQuery>(string) {
         Err(err) => Err(Error::SerdeJson(err.to_string())),
         Ok(query) => {
             match query
                 .parameters
                 .into_iter()
-                .map(|a| value_to_parameter(a))
+                .enumerate()
+                .map(|(index, value)| {
+                    value_to_parameter(value, results).map_err(|error| Error::AtParameter {
+                        index,
+                        error: Box::new(error),
+                    })
+                })
                 .collect::<Result<Vec<Parameter>, Error>>()
             {
                 Err(err) => Err(err),
                 Ok(parameters) => Ok(Query {
                     sql: query.sql,
                     parameters,
+                    columns: query.columns,
                 }),
             }
         }
     }
 }
 
-pub fn value_to_parameter(value: Value) -> Result<Parameter, Error> {
+/// `results` are the query results of earlier statements, for resolving `["Ref", index, key]`
+pub fn value_to_parameter(value: Value, results: &[Value]) -> Result<Parameter, Error> {
     match value {
         Value::Null => {
             //Err(Error::Parameter("parameter value should not be null. put 'IS NULL or 'IS NOT NULL' in sql rather than parameter.".to_string()))
             //actually need to be able to SET columns to NULL, but when selecting the above is a good rule...
             //in fact kysely transforms .where("mycol","is not",null) into "mycol IS NOT NULL" sql without sending null as parameter so no worries
-            Ok(Parameter::NULL)
+            Ok(Parameter::Null)
         }
         Value::Object(obj) => {
             //Err(Error::Parameter("parameter value should not be object".to_string()))
@@ -108,6 +140,10 @@ pub fn value_to_parameter(value: Value) -> Result<Parameter, Error> {
             }
         }
         Value::String(x) => Ok(Parameter::Str(x)),
+        Value::Array(v) if v.len() == 3 && v[0] == "Ref" => {
+            //refs resolve to plain values or tuples like ["BigInt", "1"], never to other refs
+            value_to_parameter(resolve_ref(&v, results)?, &[])
+        }
         Value::Array(v) => {
             match tuple_type(v) {
                 Err(err) => Err(err),
@@ -122,6 +158,51 @@ pub fn value_to_parameter(value: Value) -> Result<Parameter, Error> {
     }
 }
 
+/// `["Ref", 0, "insertId"]` is the insertId (or numAffectedRows) of the first statement,
+/// `["Ref", 0, "id"]` is the "id" column of the first row the first statement returned.
+/// any key other than insertId and numAffectedRows is a column, so columns named eg "truncated" work
+///
+/// `["Ref", [2, 0], "insertId"]` is the first statement inside the optional block at index 2
+fn resolve_ref(v: &[Value], results: &[Value]) -> Result<Value, Error> {
+    let path: Option<Vec<u64>> = match &v[1] {
+        Value::Array(path) => path.iter().map(Value::as_u64).collect(),
+        index => index.as_u64().map(|index| vec![index]),
+    };
+    let (Some(path), Some(key)) = (
+        path.filter(|path| matches!(path.len(), 1 | 2)),
+        v[2].as_str(),
+    ) else {
+        return Err(Error::TupleType(
+            "Ref should look like [\"Ref\", statementIndex or [blockIndex, statementIndex], \"insertId\" or \"column\"]"
+                .to_string(),
+        ));
+    };
+    let result = match path[..] {
+        [index] => results.get(index as usize),
+        [block, index] => results
+            .get(block as usize)
+            .and_then(|block| block["results"].get(index as usize)),
+        _ => None,
+    };
+    let Some(result) = result else {
+        return Err(Error::Parameter(format!(
+            "Ref to statement {:?} which has no result (yet)",
+            path
+        )));
+    };
+    let value = match key {
+        "insertId" | "numAffectedRows" => result.get(key),
+        column => result["rows"].get(0).and_then(|row| row.get(column)),
+    };
+    match value {
+        Some(value) => Ok(value.clone()),
+        None => Err(Error::Parameter(format!(
+            "Ref to statement {:?} has no \"{}\"",
+            path, key
+        ))),
+    }
+}
+
 enum TupleType {
     Date(String),
     BigInt(i64),
@@ -178,3 +259,43 @@ pub fn mysql_date_string(str: &str) -> Option<String> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refs_resolve_from_earlier_results() {
+        let results = vec![
+            serde_json::json!({ "insertId": ["BigInt", "42"], "numAffectedRows": ["BigInt", "1"], "rows": [] }),
+            serde_json::json!({ "rows": [{ "id": 7, "name": "musk" }] }),
+        ];
+        let s = r#"{"sql": "INSERT INTO Child (parentId, ownerId, name) VALUES (?, ?, ?)",
+            "parameters": [["Ref", 0, "insertId"], ["Ref", 1, "id"], ["Ref", 1, "name"]]}"#;
+        let Ok(query) = string_to_query_with_results(s, &results) else {
+            panic!("refs should resolve");
+        };
+        assert!(matches!(query.parameters[0], Parameter::Int(42)));
+        assert!(matches!(query.parameters[1], Parameter::Int(7)));
+        assert!(matches!(&query.parameters[2], Parameter::Str(x) if x == "musk"));
+
+        let s = r#"{"sql": "SELECT ?", "parameters": [1, ["Ref", 2, "insertId"]]}"#;
+        let Err(err) = string_to_query_with_results(s, &results) else {
+            panic!("statement 2 has no result");
+        };
+        assert_eq!(err.kind(), "parameter");
+        assert_eq!(err.parameter_index(), Some(1));
+        let s = r#"{"sql": "SELECT ?", "parameters": [["Ref", 1, "missing"]]}"#;
+        assert!(string_to_query_with_results(s, &results).is_err());
+        let results = vec![
+            serde_json::json!({ "rows": [{ "truncated": "column" }], "truncated": true }),
+            serde_json::json!({ "results": [{ "insertId": ["BigInt", "5"], "rows": [] }] }),
+        ];
+        let s = r#"{"sql": "SELECT ?, ?", "parameters": [["Ref", 0, "truncated"], ["Ref", [1, 0], "insertId"]]}"#;
+        let Ok(query) = string_to_query_with_results(s, &results) else {
+            panic!("refs should resolve");
+        };
+        assert!(matches!(&query.parameters[0], Parameter::Str(x) if x == "column"));
+        assert!(matches!(query.parameters[1], Parameter::Int(5)));
+    }
+}
@@ -7,7 +7,7 @@ use chrono::{DateTime, Utc};
 use serde_json::{Map, Value};
 use sqlx::{
     mysql::{MySqlColumn, MySqlRow},
-    Column, Decode, MySql, Row, TypeInfo, ValueRef,
+    Column, Decode, Describe, MySql, Row, TypeInfo, ValueRef,
 };
 
 pub fn row_to_value(row: &MySqlRow) -> Result<Value, Error> {
@@ -21,8 +21,34 @@ pub fn row_to_value(row: &MySqlRow) -> Result<Value, Error> {
     Ok(Value::Object(map))
 }
 
-pub fn rows_to_value(rows: &Vec<MySqlRow>) -> Result<Vec<Value>, Error> {
-    rows.iter().map(|row| row_to_value(row)).collect()
+pub fn rows_to_value(rows: &[MySqlRow]) -> Result<Vec<Value>, Error> {
+    rows.iter().map(row_to_value).collect()
+}
+
+/// the columns of a statement, eg
+/// `[{ "name": "id", "ordinal": 0, "type": "INT UNSIGNED", "nullable": false, "unsigned": true }]`,
+/// "nullable" is null if mysql did not say
+pub fn describe_to_value(describe: &Describe<MySql>) -> Value {
+    let columns = describe.columns().iter().map(|col| {
+        column_to_value(
+            col.name(),
+            col.ordinal(),
+            col.type_info().name(),
+            describe.nullable(col.ordinal()),
+        )
+    });
+    Value::Array(columns.collect())
+}
+
+fn column_to_value(name: &str, ordinal: usize, type_name: &str, nullable: Option<bool>) -> Value {
+    serde_json::json!({
+        "name": name,
+        "ordinal": ordinal,
+        "type": type_name,
+        "nullable": nullable,
+        //sqlx only exposes the flag through the type name
+        "unsigned": type_name.ends_with(" UNSIGNED"),
+    })
 }
 
 fn add_value_to_map(
@@ -326,7 +352,9 @@ fn col_to_value(row: &MySqlRow, col: &MySqlColumn) -> Result<Value, Error> {
 }
 
 #[cfg(test)]
+#[allow(unused_imports, clippy::assertions_on_constants)]
 mod tests {
+    use geo_types::Geometry;
     use std::io::Read;
 
     use crate::wkb::wkb_to_geom;
@@ -351,6 +379,18 @@ mod tests {
         }
     }
 
+    #[test]
+    fn column_metadata() {
+        let value = super::column_to_value("id", 0, "INT UNSIGNED", Some(false));
+        assert_eq!(value["name"], "id");
+        assert_eq!(value["type"], "INT UNSIGNED");
+        assert_eq!(value["unsigned"], true);
+        assert_eq!(value["nullable"], false);
+        let value = super::column_to_value("title", 1, "VARCHAR", None);
+        assert_eq!(value["unsigned"], false);
+        assert!(value["nullable"].is_null());
+    }
+
     #[test]
     fn spatial_reference_system_id() {
         let expected: Vec<u8> = vec![230, 16, 0, 0];
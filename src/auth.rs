@@ -0,0 +1,495 @@
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    http::header::Header,
+    web, FromRequest, HttpMessage, HttpRequest,
+};
+use actix_web_httpauth::headers::authorization::{Authorization, Basic, Bearer};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, SaltString},
+    Argon2, PasswordHasher, PasswordVerifier,
+};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sqlx_mysql_json::Limits;
+use std::{
+    collections::BTreeMap,
+    fs,
+    future::{ready, Ready},
+    sync::RwLock,
+    time::{Duration, SystemTime},
+};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    config::{Config, JwtConfig, UserConfig},
+    error::Error,
+};
+
+//in javascript:
+//str = Buffer.from(`${userID}:${password}`).toString("base64")
+//headers: {Authorization: `Basic ${str}`},
+//or
+//headers: {Authorization: `Bearer ${jwt}`},
+
+/// `Authorization: Basic ...` or `Authorization: Bearer ...`
+pub enum Credential {
+    Basic(Basic),
+    Bearer(Bearer),
+}
+
+impl FromRequest for Credential {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let credential = if let Ok(auth) = Authorization::<Basic>::parse(req) {
+            Ok(Credential::Basic(auth.into_scheme()))
+        } else if let Ok(auth) = Authorization::<Bearer>::parse(req) {
+            Ok(Credential::Bearer(auth.into_scheme()))
+        } else {
+            Err(Error::Auth)
+        };
+        ready(credential)
+    }
+}
+
+/// the authenticated caller, inserted into request extensions by `validate_credentials`
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub username: String,
+    databases: Databases,
+    pub read_only: bool,
+    pub named_queries_only: bool,
+    /// caps on SELECT results for this caller
+    pub limits: Limits,
+}
+
+#[derive(Clone, Debug)]
+enum Databases {
+    All,
+    Only(Vec<String>),
+}
+
+impl Principal {
+    /// `NamedQueriesOnly` for callers that may not send their own sql
+    pub fn may_send_sql(&self) -> Result<(), Error> {
+        match self.named_queries_only {
+            true => Err(Error::NamedQueriesOnly(self.username.clone())),
+            false => Ok(()),
+        }
+    }
+
+    pub fn may_use(&self, db: &str) -> bool {
+        match &self.databases {
+            Databases::All => true,
+            Databases::Only(names) => names.iter().any(|name| name == db),
+        }
+    }
+}
+
+impl Databases {
+    fn from_names(names: Vec<String>) -> Databases {
+        match names.iter().any(|db| db == "*") {
+            true => Databases::All,
+            false => Databases::Only(names),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    /// database names, `"*"` means all
+    dbs: Vec<String>,
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default)]
+    named_queries_only: bool,
+    max_rows: Option<u64>,
+    max_result_bytes: Option<usize>,
+}
+
+struct JwtKeys {
+    hs256: Option<DecodingKey>,
+    rs256: Option<DecodingKey>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl JwtKeys {
+    fn new(config: &JwtConfig) -> Result<JwtKeys, String> {
+        let rs256 = match config.rs256_public_key_file.as_deref() {
+            None => None,
+            Some(path) => {
+                let pem =
+                    fs::read(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+                let key = DecodingKey::from_rsa_pem(&pem)
+                    .map_err(|err| format!("could not parse {}: {}", path, err))?;
+                Some(key)
+            }
+        };
+        Ok(JwtKeys {
+            hs256: config
+                .hs256_secret
+                .as_ref()
+                .map(|secret| DecodingKey::from_secret(secret.as_bytes())),
+            rs256,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+        })
+    }
+
+    /// checks signature, "exp" and if configured "iss" and "aud"
+    fn verify(&self, token: &str) -> Option<Claims> {
+        let algorithm = jsonwebtoken::decode_header(token).ok()?.alg;
+        let key = match algorithm {
+            Algorithm::HS256 => self.hs256.as_ref()?,
+            Algorithm::RS256 => self.rs256.as_ref()?,
+            _ => return None,
+        };
+        let mut validation = Validation::new(algorithm);
+        if let Some(issuer) = self.issuer.as_ref() {
+            validation.set_issuer(&[issuer]);
+        }
+        match self.audience.as_ref() {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let claims = jsonwebtoken::decode::<Claims>(token, key, &validation).ok()?;
+        Some(claims.claims)
+    }
+}
+
+/// verified for unknown usernames so they take as long as known ones, `myapp hash "not a user"`
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$MbqEvoa5AkaHP7C8ShxKtA$kHK6SzDyIT7LL8T7ZHDwvMoPyZzDWpnKobzhLNLrJfw";
+
+struct Key {
+    hash: String,
+    expires: Option<DateTime<Utc>>,
+}
+
+impl Key {
+    /// argon2 and bcrypt verification both compare in constant time
+    fn verify(&self, secret: &str, now: DateTime<Utc>) -> bool {
+        if self.expires.is_some_and(|expires| expires <= now) {
+            return false;
+        }
+        if self.hash.starts_with("$argon2") {
+            match PasswordHash::new(&self.hash) {
+                Err(_) => false,
+                Ok(hash) => Argon2::default()
+                    .verify_password(secret.as_bytes(), &hash)
+                    .is_ok(),
+            }
+        } else {
+            bcrypt::verify(secret, &self.hash).unwrap_or(false)
+        }
+    }
+}
+
+struct User {
+    keys: Vec<Key>,
+    databases: Databases,
+    read_only: bool,
+    named_queries_only: bool,
+    limits: Limits,
+}
+
+/// usernames mapped to their hashed keys and allowed databases
+pub struct Credentials {
+    users: RwLock<BTreeMap<String, User>>,
+    /// `DB_HTTP_AUTH_PASSWORD`, used for any username when no users are configured
+    shared_password: Option<String>,
+    file: Option<String>,
+    database_names: Vec<String>,
+    jwt: Option<JwtKeys>,
+}
+
+impl Credentials {
+    pub fn new(config: &Config, shared_password: Option<String>) -> Result<Credentials, String> {
+        let credentials = Credentials {
+            users: RwLock::new(BTreeMap::new()),
+            shared_password,
+            file: config.credentials_file.clone(),
+            database_names: config.databases.keys().cloned().collect(),
+            jwt: config.jwt.as_ref().map(JwtKeys::new).transpose()?,
+        };
+        if let Some(path) = credentials.file.as_deref() {
+            credentials.reload(path)?;
+        }
+        if credentials.users.read().unwrap().is_empty()
+            && credentials.shared_password.is_none()
+            && credentials.jwt.is_none()
+        {
+            return Err("no users configured, expected DB_HTTP_AUTH_PASSWORD in env".to_string());
+        }
+        Ok(credentials)
+    }
+
+    fn reload(&self, path: &str) -> Result<(), String> {
+        let s =
+            fs::read_to_string(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+        let users: BTreeMap<String, UserConfig> =
+            serde_json::from_str(&s).map_err(|err| format!("could not parse {}: {}", path, err))?;
+        let users = self.users_from_config(users)?;
+        *self.users.write().unwrap() = users;
+        Ok(())
+    }
+
+    fn users_from_config(
+        &self,
+        config: BTreeMap<String, UserConfig>,
+    ) -> Result<BTreeMap<String, User>, String> {
+        let mut users = BTreeMap::new();
+        for (username, user) in config.into_iter() {
+            for db in user.databases.iter() {
+                if db != "*" && !self.database_names.contains(db) {
+                    return Err(format!("user '{}' has unknown database '{}'", username, db));
+                }
+            }
+            for key in user.keys.iter() {
+                let valid = match key.hash.starts_with("$argon2") {
+                    true => PasswordHash::new(&key.hash).is_ok(),
+                    false => key.hash.starts_with("$2"),
+                };
+                if !valid {
+                    return Err(format!(
+                        "user '{}' has a key that is not an argon2 or bcrypt hash",
+                        username
+                    ));
+                }
+            }
+            let databases = Databases::from_names(user.databases);
+            let keys = user
+                .keys
+                .into_iter()
+                .map(|key| Key {
+                    hash: key.hash,
+                    expires: key.expires,
+                })
+                .collect();
+            let user = User {
+                keys,
+                databases,
+                read_only: user.read_only,
+                named_queries_only: user.named_queries_only,
+                limits: Limits {
+                    max_rows: user.max_rows,
+                    max_bytes: user.max_result_bytes,
+                },
+            };
+            users.insert(username, user);
+        }
+        Ok(users)
+    }
+
+    /// reload the credentials file whenever it changes, so keys can be rotated without a restart
+    pub fn watch(credentials: web::Data<Credentials>) {
+        let Some(path) = credentials.file.clone() else {
+            return;
+        };
+        tokio::spawn(async move {
+            let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+            let mut last_modified: Option<SystemTime> = modified(&path);
+            let mut interval = tokio::time::interval(Duration::from_secs(10));
+            loop {
+                interval.tick().await;
+                let now_modified = modified(&path);
+                if now_modified == last_modified {
+                    continue;
+                }
+                last_modified = now_modified;
+                match credentials.reload(&path) {
+                    Ok(_) => println!("reloaded credentials from {}", path),
+                    Err(err) => println!("keeping old credentials, {}", err),
+                }
+            }
+        });
+    }
+
+    /// slow on purpose (argon2/bcrypt), call from a blocking thread
+    fn authenticate(&self, username: &str, secret: &str) -> Option<Principal> {
+        let users = self.users.read().unwrap();
+        if users.is_empty() {
+            let shared = self.shared_password.as_deref()?;
+            return match bool::from(shared.as_bytes().ct_eq(secret.as_bytes())) {
+                true => Some(Principal {
+                    username: username.to_string(),
+                    databases: Databases::All,
+                    read_only: false,
+                    named_queries_only: false,
+                    limits: Limits::default(),
+                }),
+                false => None,
+            };
+        }
+        let now = Utc::now();
+        let Some(user) = users.get(username) else {
+            let dummy = Key {
+                hash: DUMMY_HASH.to_string(),
+                expires: None,
+            };
+            dummy.verify(secret, now);
+            return None;
+        };
+        match user.keys.iter().any(|key| key.verify(secret, now)) {
+            true => Some(Principal {
+                username: username.to_string(),
+                databases: user.databases.clone(),
+                read_only: user.read_only,
+                named_queries_only: user.named_queries_only,
+                limits: user.limits,
+            }),
+            false => None,
+        }
+    }
+
+    fn authenticate_token(&self, token: &str) -> Option<Principal> {
+        let claims = self.jwt.as_ref()?.verify(token)?;
+        Some(Principal {
+            username: claims.sub,
+            databases: Databases::from_names(claims.dbs),
+            read_only: claims.read_only,
+            named_queries_only: claims.named_queries_only,
+            limits: Limits {
+                max_rows: claims.max_rows,
+                max_bytes: claims.max_result_bytes,
+            },
+        })
+    }
+}
+
+pub fn hash(secret: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+pub async fn validate_credentials(
+    req: ServiceRequest,
+    credential: Credential,
+) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+    let store = req.app_data::<web::Data<Credentials>>().cloned();
+    let principal = match (store, credential) {
+        (None, _) => None,
+        (Some(store), Credential::Bearer(bearer)) => store.authenticate_token(bearer.token()),
+        (Some(store), Credential::Basic(basic)) => match basic.password() {
+            None => None,
+            Some(pw) => {
+                let username = basic.user_id().to_string();
+                let pw = pw.to_string();
+                web::block(move || store.authenticate(&username, &pw))
+                    .await
+                    .unwrap_or(None)
+            }
+        },
+    };
+    match principal {
+        None => Err((Error::Auth.into(), req)),
+        Some(principal) => {
+            req.extensions_mut().insert(principal);
+            Ok(req)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `myapp hash pw1`
+    const ARGON2_PW1: &str =
+        "$argon2id$v=19$m=19456,t=2,p=1$sE/QX2iKj+toVC9sZ0hmSA$JAwoZBQVSJQDSsp+TteoeY2Y0z+gEi0OujlZXin0hys";
+
+    fn credentials(users: &str) -> Credentials {
+        let config: Config = serde_json::from_str(
+            r#"{"databases": {"db": {"url": "mysql://a"}, "musker": {"url": "mysql://b"}}}"#,
+        )
+        .unwrap();
+        let credentials = Credentials::new(&config, Some("shared".to_string())).unwrap();
+        let users = credentials
+            .users_from_config(serde_json::from_str(users).unwrap())
+            .unwrap();
+        *credentials.users.write().unwrap() = users;
+        credentials
+    }
+
+    #[test]
+    fn users_are_limited_to_their_databases() {
+        let bcrypt_pw2 = bcrypt::hash("pw2", 4).unwrap();
+        let credentials = credentials(&format!(
+            r#"{{
+                "frontend": {{"keys": [{{"hash": "{}"}}], "databases": ["musker"]}},
+                "admin": {{"keys": [{{"hash": "{}"}}], "databases": ["*"], "read_only": true}}
+            }}"#,
+            ARGON2_PW1, bcrypt_pw2
+        ));
+        let frontend = credentials.authenticate("frontend", "pw1").unwrap();
+        assert!(frontend.may_use("musker"));
+        assert!(!frontend.may_use("db"));
+        assert!(!frontend.read_only);
+        let admin = credentials.authenticate("admin", "pw2").unwrap();
+        assert!(admin.may_use("db"));
+        assert!(admin.read_only);
+        assert!(credentials.authenticate("frontend", "pw2").is_none());
+        assert!(credentials.authenticate("nobody", "pw1").is_none());
+        assert!(credentials.authenticate("admin", "shared").is_none());
+    }
+
+    #[test]
+    fn expired_keys_are_rejected() {
+        let bcrypt_new = bcrypt::hash("new", 4).unwrap();
+        let credentials = credentials(&format!(
+            r#"{{"frontend": {{"databases": ["musker"], "keys": [
+                {{"hash": "{}", "expires": "2020-01-01T00:00:00Z"}},
+                {{"hash": "{}", "expires": "2999-01-01T00:00:00Z"}}
+            ]}}}}"#,
+            ARGON2_PW1, bcrypt_new
+        ));
+        assert!(credentials.authenticate("frontend", "pw1").is_none());
+        assert!(credentials.authenticate("frontend", "new").is_some());
+    }
+
+    #[test]
+    fn bearer_tokens_carry_their_scope() {
+        let config: Config = serde_json::from_str(
+            r#"{"databases": {"db": {"url": "mysql://a"}}, "jwt": {"hs256_secret": "s3cret"}}"#,
+        )
+        .unwrap();
+        let credentials = Credentials::new(&config, None).unwrap();
+        let token = |secret: &str, exp: i64| {
+            let claims = serde_json::json!({"sub": "frontend", "exp": exp, "dbs": ["db"], "read_only": true});
+            let key = jsonwebtoken::EncodingKey::from_secret(secret.as_bytes());
+            jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap()
+        };
+        let in_an_hour = Utc::now().timestamp() + 3600;
+
+        let principal = credentials
+            .authenticate_token(&token("s3cret", in_an_hour))
+            .unwrap();
+        assert_eq!(principal.username, "frontend");
+        assert!(principal.read_only);
+        assert!(principal.may_use("db"));
+        assert!(!principal.may_use("musker"));
+        assert!(credentials
+            .authenticate_token(&token("wrong", in_an_hour))
+            .is_none());
+        assert!(credentials
+            .authenticate_token(&token("s3cret", in_an_hour - 7200))
+            .is_none());
+    }
+
+    #[test]
+    fn shared_password_without_users() {
+        let credentials = credentials("{}");
+        assert!(credentials
+            .authenticate("anyone", "shared")
+            .unwrap()
+            .may_use("db"));
+        assert!(credentials.authenticate("anyone", "wrong").is_none());
+    }
+}
@@ -0,0 +1,157 @@
+use sqlx::{Connection, Executor, MySqlConnection, MySqlPool, Row};
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::error::Error;
+
+/// how long the statements of a request may run on a database before they are killed
+#[derive(Clone)]
+pub struct Deadline {
+    pool: MySqlPool,
+    timeout: Option<Duration>,
+}
+
+impl Deadline {
+    pub fn new(pool: MySqlPool, timeout: Option<Duration>) -> Deadline {
+        Deadline { pool, timeout }
+    }
+
+    /// a watchdog for statements on `conn`, looks up its `CONNECTION_ID()` if there is a timeout,
+    /// the timeout starts now
+    pub async fn watchdog(&self, conn: &mut MySqlConnection) -> Result<Watchdog, Error> {
+        let connection_id = match self.timeout {
+            None => None,
+            Some(_) => {
+                //eg a lost connection, reported like any other failed query (503)
+                let row = conn
+                    .fetch_one("SELECT CONNECTION_ID()")
+                    .await
+                    .map_err(|err| Error::Query(err.into()))?;
+                Some(row.try_get::<u64, _>(0).map_err(|_| Error::Internal)?)
+            }
+        };
+        Ok(Watchdog {
+            pool: self.pool.clone(),
+            timeout: self.timeout,
+            expires: self.timeout.map(|timeout| Instant::now() + timeout),
+            connection_id,
+            abandoned: AtomicBool::new(false),
+            disarmed: AtomicBool::new(false),
+        })
+    }
+}
+
+/// the deadline for statements on one connection, shared by all statements of a request
+/// so a long `/transaction` or its retries cannot take a multiple of the timeout
+pub struct Watchdog {
+    pool: MySqlPool,
+    timeout: Option<Duration>,
+    expires: Option<Instant>,
+    connection_id: Option<u64>,
+    abandoned: AtomicBool,
+    disarmed: AtomicBool,
+}
+
+/// how long a killed statement gets to notice, `KILL QUERY` itself might not get through
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+impl Watchdog {
+    /// await `statement`, if it still runs when the time is up it is killed with `KILL QUERY` from a side connection.
+    /// a statement that would start after that is not run at all
+    ///
+    /// the statement is still awaited after the kill, so its connection is left idle and usable
+    /// instead of dropped halfway through reading a result. if it does not stop within `KILL_GRACE`
+    /// it is dropped anyway and the connection is `abandoned()`
+    pub async fn run<F: Future>(&self, statement: F) -> Result<F::Output, Error> {
+        let (Some(timeout), Some(expires), Some(connection_id)) =
+            (self.timeout, self.expires, self.connection_id)
+        else {
+            return Ok(statement.await);
+        };
+        if self.abandoned() || Instant::now() >= expires {
+            return Err(Error::Timeout(timeout.as_millis() as u64));
+        }
+        tokio::pin!(statement);
+        tokio::select! {
+            output = &mut statement => return Ok(output),
+            _ = tokio::time::sleep_until(expires) => {}
+        }
+        if self.disarmed.load(Ordering::Relaxed) {
+            return Ok(statement.await);
+        }
+        let kill = tokio::time::timeout(KILL_GRACE, self.kill(connection_id));
+        match kill.await {
+            Err(_) => println!("could not KILL QUERY {}: no answer", connection_id),
+            Ok(Err(err)) => println!("could not KILL QUERY {}: {}", connection_id, err),
+            Ok(Ok(_)) => {}
+        }
+        if tokio::time::timeout(KILL_GRACE, statement).await.is_err() {
+            self.abandoned.store(true, Ordering::Relaxed);
+        }
+        Err(Error::Timeout(timeout.as_millis() as u64))
+    }
+
+    /// start the timeout over, for the next request on a `/tx` transaction
+    pub fn restart(&mut self) {
+        self.expires = self.timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    /// let the running statement go past the deadline, eg a stream that got its first row
+    pub fn disarm(&self) {
+        self.disarmed.store(true, Ordering::Relaxed);
+    }
+
+    /// true if a statement was dropped halfway, its connection must be closed (`detach()`)
+    /// instead of used again or returned to the pool
+    pub fn abandoned(&self) -> bool {
+        self.abandoned.load(Ordering::Relaxed)
+    }
+
+    /// not from the pool, it might be exhausted by the very statements that need killing
+    async fn kill(&self, connection_id: u64) -> Result<(), sqlx::Error> {
+        let options = self.pool.connect_options();
+        let mut side = MySqlConnection::connect_with(&options).await?;
+        side.execute(format!("KILL QUERY {}", connection_id).as_str())
+            .await?;
+        side.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn statements_after_the_deadline_are_not_run() {
+        let pool = MySqlPool::connect_lazy("mysql://u:p@127.0.0.1:1/db").unwrap();
+        let timeout = Duration::from_millis(100);
+        let mut watchdog = Watchdog {
+            pool,
+            timeout: Some(timeout),
+            expires: Some(Instant::now() - timeout),
+            connection_id: Some(1),
+            abandoned: AtomicBool::new(false),
+            disarmed: AtomicBool::new(false),
+        };
+        let mut ran = false;
+        let result = watchdog.run(async { ran = true }).await;
+        assert!(matches!(result, Err(Error::Timeout(100))));
+        assert!(!ran);
+
+        watchdog.restart();
+        assert!(watchdog.run(async { 1 }).await.is_ok());
+
+        //would be killed at the deadline, but disarmed before it
+        watchdog.restart();
+        let statement = async {
+            watchdog.disarm();
+            tokio::time::sleep(timeout * 2).await;
+        };
+        assert!(watchdog.run(statement).await.is_ok());
+        assert!(!watchdog.abandoned());
+    }
+}
@@ -1,38 +1,231 @@
 use actix_web::{
     error,
-    http::{header::ContentType, StatusCode},
+    http::{header, StatusCode},
     HttpResponse,
 };
 use derive_more::{Display, Error};
+use sqlx_mysql_json::error::Class;
 
 #[derive(Debug, Display, Error)]
 pub enum Error {
     #[display(fmt = "internal error")]
-    InternalError,
+    Internal,
 
     #[display(fmt = "auth error")]
-    AuthError,
+    Auth,
 
     #[display(fmt = "bad request")]
     BadClientData,
 
-    #[display(fmt = "timeout")]
-    Timeout,
+    #[display(
+        fmt = "request did not finish within {}ms, its statement was killed",
+        _0
+    )]
+    Timeout(#[error(not(source))] u64),
+
+    #[display(fmt = "unknown db '{}', expected one of: {}", _0, _1)]
+    UnknownDatabase(#[error(not(source))] String, #[error(not(source))] String),
+
+    #[display(fmt = "user '{}' may not use db '{}'", _0, _1)]
+    Forbidden(#[error(not(source))] String, #[error(not(source))] String),
+
+    #[display(fmt = "the key for '{}' is read-only", _0)]
+    ReadOnly(#[error(not(source))] String),
+
+    #[display(fmt = "the key for '{}' may only call named queries", _0)]
+    NamedQueriesOnly(#[error(not(source))] String),
+
+    #[display(fmt = "unknown query '{}'", _0)]
+    UnknownQuery(#[error(not(source))] String),
+
+    #[display(fmt = "db '{}' is unavailable, try again later", _0)]
+    Unavailable(#[error(not(source))] String),
+
+    #[display(fmt = "unknown transaction '{}'", _0)]
+    UnknownTransaction(#[error(not(source))] String),
+
+    #[display(fmt = "couldnt acquire connection from pool")]
+    NoConnection,
+
+    /// START TRANSACTION, COMMIT etc failed
+    #[display(fmt = "failed to {}: {}", _0, _1)]
+    Transaction(
+        #[error(not(source))] String,
+        #[error(not(source))] sqlx_mysql_json::error::Error,
+    ),
+
+    #[display(fmt = "only SELECT queries can be streamed")]
+    NotSelect,
+
+    /// DDL, COMMIT and the like would silently end a `/tx` transaction
+    #[display(fmt = "statement would end the transaction")]
+    EndsTransaction,
+
+    /// a `/transaction` statement affected or returned an unexpected number of rows
+    #[display(fmt = "{}", _0)]
+    Expectation(#[error(not(source))] String),
+
+    #[display(fmt = "invalid request: {}", _0)]
+    Request(#[error(not(source))] String),
+
+    /// the query itself failed, eg bad parameters or a mysql error
+    #[display(fmt = "{}", _0)]
+    Query(#[error(not(source))] sqlx_mysql_json::error::Error),
+}
+
+impl From<sqlx_mysql_json::error::Error> for Error {
+    fn from(err: sqlx_mysql_json::error::Error) -> Self {
+        Error::Query(err)
+    }
+}
+
+impl Error {
+    fn kind(&self) -> &'static str {
+        match self {
+            Error::Internal => "internal",
+            Error::Auth => "auth",
+            Error::BadClientData => "badRequest",
+            Error::Timeout(..) => "timeout",
+            Error::UnknownDatabase(..) => "unknownDatabase",
+            Error::Forbidden(..) => "forbidden",
+            Error::ReadOnly(..) => "readOnly",
+            Error::NamedQueriesOnly(..) => "namedQueriesOnly",
+            Error::UnknownQuery(..) => "unknownQuery",
+            Error::Unavailable(..) => "unavailable",
+            Error::UnknownTransaction(..) => "unknownTransaction",
+            Error::NoConnection => "noConnection",
+            Error::Transaction(..) => "transaction",
+            Error::NotSelect => "notSelect",
+            Error::EndsTransaction => "endsTransaction",
+            Error::Expectation(..) => "expectation",
+            Error::Request(..) => "request",
+            Error::Query(err) => err.kind(),
+        }
+    }
+
+    /// `{ "kind": "sql", "message": "...", "code": 1062, "sqlstate": "23000", "parameterIndex": null, "retryable": false }`,
+    /// the same shape for every error so clients can branch on `kind` and `code`
+    pub fn to_value(&self) -> serde_json::Value {
+        match self {
+            Error::Query(err) => err.to_value(),
+            Error::Transaction(_, err) => {
+                let mut value = err.to_value();
+                value["kind"] = self.kind().into();
+                value["message"] = self.to_string().into();
+                value
+            }
+            _ => serde_json::json!({
+                "kind": self.kind(),
+                "message": self.to_string(),
+                "code": null,
+                "sqlstate": null,
+                "parameterIndex": null,
+                "retryable": matches!(self, Error::Unavailable(..) | Error::NoConnection),
+            }),
+        }
+    }
 }
 
 impl error::ResponseError for Error {
+    /// `{ "error": {...} }` with `to_value()` inside,
+    /// a 401 challenges the client like the basic auth extractor did
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::html())
-            .body(self.to_string())
+        let mut response = HttpResponse::build(self.status_code());
+        if let Error::Auth = self {
+            response.insert_header((header::WWW_AUTHENTICATE, "Basic, Bearer"));
+        }
+        response.json(serde_json::json!({ "error": self.to_value() }))
     }
 
     fn status_code(&self) -> StatusCode {
-        match *self {
-            Error::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        match self {
+            Error::Internal => StatusCode::INTERNAL_SERVER_ERROR,
             Error::BadClientData => StatusCode::BAD_REQUEST,
-            Error::Timeout => StatusCode::GATEWAY_TIMEOUT,
-            Error::AuthError => StatusCode::UNAUTHORIZED,
+            Error::Timeout(..) => StatusCode::GATEWAY_TIMEOUT,
+            Error::Auth => StatusCode::UNAUTHORIZED,
+            Error::UnknownDatabase(..) => StatusCode::BAD_REQUEST,
+            Error::Forbidden(..) => StatusCode::FORBIDDEN,
+            Error::ReadOnly(..) => StatusCode::FORBIDDEN,
+            Error::NamedQueriesOnly(..) => StatusCode::FORBIDDEN,
+            Error::UnknownQuery(..) => StatusCode::NOT_FOUND,
+            Error::Unavailable(..) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::UnknownTransaction(..) => StatusCode::NOT_FOUND,
+            Error::NoConnection => StatusCode::SERVICE_UNAVAILABLE,
+            //our own statement, so not the client's fault
+            Error::Transaction(_, err) => match err.class() {
+                Class::Client | Class::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+                class => class_status(class),
+            },
+            Error::NotSelect => StatusCode::BAD_REQUEST,
+            Error::EndsTransaction => StatusCode::BAD_REQUEST,
+            Error::Expectation(..) => StatusCode::CONFLICT,
+            Error::Request(..) => StatusCode::BAD_REQUEST,
+            Error::Query(err) => class_status(err.class()),
         }
     }
 }
+
+fn class_status(class: Class) -> StatusCode {
+    match class {
+        Class::Client => StatusCode::BAD_REQUEST,
+        Class::Conflict => StatusCode::CONFLICT,
+        Class::Retryable | Class::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Class::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::ResponseError;
+
+    #[test]
+    fn every_error_has_the_same_envelope() {
+        let duplicate = Error::from(sqlx_mysql_json::error::Error::Database {
+            number: 1062,
+            sqlstate: Some("23000".to_string()),
+            message: "Duplicate entry '1' for key 'PRIMARY'".to_string(),
+        });
+        let value = duplicate.to_value();
+        assert_eq!(value["kind"], "sql");
+        assert_eq!(value["code"], 1062);
+        assert_eq!(value["sqlstate"], "23000");
+        assert_eq!(duplicate.status_code(), StatusCode::CONFLICT);
+        assert_eq!(value["retryable"], false);
+
+        let value = Error::Unavailable("musker".to_string()).to_value();
+        assert_eq!(value["kind"], "unavailable");
+        assert!(value["code"].is_null());
+        assert!(value.get("parameterIndex").is_some());
+        assert_eq!(value["retryable"], true);
+
+        let deadlock = Error::from(sqlx_mysql_json::error::Error::Database {
+            number: 1213,
+            sqlstate: Some("40001".to_string()),
+            message: "Deadlock found when trying to get lock".to_string(),
+        });
+        assert_eq!(deadlock.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(deadlock.to_value()["retryable"], true);
+
+        let lost = Error::Transaction(
+            "COMMIT".to_string(),
+            sqlx_mysql_json::error::Error::from(sqlx::Error::PoolTimedOut),
+        );
+        assert_eq!(lost.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        let value = lost.to_value();
+        assert_eq!(value["kind"], "transaction");
+        assert_eq!(value["retryable"], true);
+    }
+
+    #[test]
+    fn unauthorized_responses_challenge_the_client() {
+        let response = Error::Auth.error_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Basic, Bearer"
+        );
+        let response = Error::NoConnection.error_response();
+        assert!(response.headers().get(header::WWW_AUTHENTICATE).is_none());
+    }
+}
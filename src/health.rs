@@ -0,0 +1,55 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use futures::future::join_all;
+use serde_json::{json, Map, Value};
+
+use crate::pools::Pools;
+
+/// the process is up, says nothing about the databases
+#[get("/healthz")]
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json("ok")
+}
+
+/// pings every database, 503 if a required one does not answer
+///
+/// returns something like this:
+///```json
+///{
+///  "ready": true,
+///  "databases": {
+///    "db": { "required": true, "reachable": true, "latencyMs": 1.2, "size": 2, "idle": 1 },
+///    "musker": { "required": false, "reachable": false, "error": "no answer within 2000ms", "size": 0, "idle": 0 }
+///  }
+///}
+///```
+#[get("/readyz")]
+pub async fn readyz(pools: web::Data<Pools>) -> impl Responder {
+    let pings = join_all(pools.iter().map(|db| db.ping(pools.ready_timeout))).await;
+
+    let mut ready = true;
+    let mut databases = Map::new();
+    for (db, ping) in pools.iter().zip(pings) {
+        let mut status = json!({
+            "required": db.required,
+            "reachable": ping.is_ok(),
+            "size": db.pool.size(),
+            "idle": db.pool.num_idle(),
+        });
+        match ping {
+            Ok(latency) => status["latencyMs"] = json!(latency.as_secs_f64() * 1000.0),
+            Err(err) => {
+                status["error"] = json!(err);
+                if db.required {
+                    ready = false;
+                }
+            }
+        }
+        databases.insert(db.name.clone(), status);
+    }
+
+    let body = json!({ "ready": ready, "databases": Value::Object(databases) });
+    match ready {
+        true => HttpResponse::Ok().json(body),
+        false => HttpResponse::ServiceUnavailable().json(body),
+    }
+}
@@ -1,79 +1,85 @@
-use actix_web::{dev::ServiceRequest, web, App, HttpServer};
+use actix_web::{web, App, HttpServer};
+mod auth;
+mod config;
+mod deadline;
 mod error;
+mod health;
 mod pools;
+mod queries;
 mod routes;
+mod tx;
 
-use actix_web_httpauth::{extractors::basic::BasicAuth, middleware::HttpAuthentication};
+use actix_web_httpauth::middleware::HttpAuthentication;
 use dotenv::dotenv;
-use sqlx::mysql::MySqlPoolOptions;
-use std::{env, sync::OnceLock};
-
-//in javascript:
-//str = Buffer.from(`${userID}:${password}`).toString("base64")
-//headers: {Authorization: `Basic ${str}`},
-
-async fn validate_credentials(
-    req: ServiceRequest,
-    credentials: BasicAuth,
-) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
-    match credentials.password() {
-        None => Err((error::Error::AuthError.into(), req)),
-        Some(pw) => {
-            if pw == auth_password() {
-                Ok(req)
-            } else {
-                Err((error::Error::AuthError.into(), req))
-            }
-        }
-    }
-}
-
-fn auth_password() -> &'static str {
-    static PASSWORD: OnceLock<String> = OnceLock::new();
-    PASSWORD.get_or_init(|| env::var("DB_HTTP_AUTH_PASSWORD").unwrap())
-}
+use std::{env, time::Duration};
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
+    if let Some(secret) = env::args()
+        .nth(1)
+        .filter(|arg| arg == "hash")
+        .and(env::args().nth(2))
+    {
+        //`myapp hash <secret>` prints an argon2 hash for the credentials file
+        println!("{}", auth::hash(&secret));
+        return Ok(());
+    }
     println!("starting http api (tag 0.33-multidb)");
     dotenv().ok();
-    let database_url = env::var("DATABASE_URL").expect("expected DATABASE_URL in env");
-    let database_url_musker =
-        env::var("DATABASE_URL_MUSKER").expect("expected DATABASE_URL_MUSKER in env");
-    let database_url_svgbattle =
-        env::var("DATABASE_URL_SVGBATTLE").expect("expected DATABASE_URL_SVGBATTLE in env");
+    let config = config::Config::load().unwrap_or_else(|err| panic!("{}", err));
     let addrs = env::var("DB_HTTP_LISTEN_ADRESS").expect("expected DB_HTTP_LISTEN_ADRESS in env");
-    let _x = env::var("DB_HTTP_AUTH_PASSWORD").expect("expected DB_HTTP_AUTH_PASSWORD in env");
+    let credentials = web::Data::new(
+        auth::Credentials::new(&config, env::var("DB_HTTP_AUTH_PASSWORD").ok())
+            .unwrap_or_else(|err| panic!("{}", err)),
+    );
+    auth::Credentials::watch(credentials.clone());
+    let queries =
+        web::Data::new(queries::Queries::load(&config).unwrap_or_else(|err| panic!("{}", err)));
 
-    println!("connecting to db and creating pools...");
+    println!("creating pools...");
     //let pool = web::Data::new(MySqlPoolOptions::new().max_connections(10).connect(&database_url).await.unwrap());
 
-    let pools = web::Data::new(pools::Pools {
-        db: MySqlPoolOptions::new()
-            .max_connections(2) //default is 10
-            .connect(&database_url)
-            .await
-            .unwrap(),
-        musker: MySqlPoolOptions::new()
-            .max_connections(2)
-            .connect(&database_url_musker)
-            .await
-            .unwrap(),
-        svgbattle: MySqlPoolOptions::new()
-            .max_connections(2)
-            .connect(&database_url_svgbattle)
-            .await
-            .unwrap(),
-    });
-    println!("...pool created");
+    let pools = web::Data::new(pools::Pools::new(&config).expect("invalid database url"));
+    println!("databases: {}", pools.names().join(", "));
+    pools.connect_in_background();
+    println!("...pools created, connecting in background");
+    let transactions = web::Data::new(tx::Transactions::new(Duration::from_secs(
+        config.tx_idle_timeout_secs,
+    )));
+    tx::Transactions::spawn_reaper(transactions.clone());
+    let max_body_bytes = config.max_body_bytes;
     println!("http api listening on '{:?}'", addrs);
     HttpServer::new(move || {
-        let auth = HttpAuthentication::basic(validate_credentials);
+        let auth = HttpAuthentication::with_fn(auth::validate_credentials);
         App::new()
             .app_data(pools.clone())
-            .wrap(auth)
-            .service(routes::root)
-            .service(routes::transaction)
+            .app_data(credentials.clone())
+            .app_data(queries.clone())
+            .app_data(transactions.clone())
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(max_body_bytes)
+                    .error_handler(|err, _| error::Error::Request(err.to_string()).into()),
+            )
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|err, _| error::Error::Request(err.to_string()).into()),
+            )
+            .service(health::healthz)
+            .service(health::readyz)
+            .service(
+                web::scope("")
+                    .wrap(auth)
+                    .service(routes::root)
+                    .service(routes::post_query)
+                    .service(routes::named_query)
+                    .service(routes::batch)
+                    .service(routes::transaction)
+                    .service(tx::begin)
+                    .service(tx::statements)
+                    .service(tx::commit)
+                    .service(tx::rollback),
+            )
     })
     .bind(addrs)?
     .run()
@@ -1,26 +1,356 @@
-use actix_web::{
-    http::header::{HeaderMap, HeaderName, HeaderValue},
-    web,
+use actix_web::{HttpMessage, HttpRequest};
+use sqlx::{mysql::MySqlPoolOptions, pool::PoolConnection, Connection, Executor, MySql, MySqlPool};
+use sqlx_mysql_json::Limits;
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use sqlx::MySqlPool;
 
+use crate::{
+    auth::Principal,
+    config::{Config, DatabaseConfig, RetryConfig},
+    deadline::{Deadline, Watchdog},
+    error::Error,
+};
+
+const CONNECTING: u8 = 0;
+const AVAILABLE: u8 = 1;
+const DOWN: u8 = 2;
+
+/// a lazy pool plus whether we managed to connect to it yet
+pub struct Database {
+    pub name: String,
+    pub pool: MySqlPool,
+    pub required: bool,
+    /// caps on SELECT results from this database
+    pub limits: Limits,
+    /// statements running longer than this are killed
+    pub query_timeout: Option<Duration>,
+    /// how often a `/transaction` is attempted when it deadlocks
+    pub transaction_retry: Option<RetryConfig>,
+    state: AtomicU8,
+    retry: RetryConfig,
+}
+
+impl Database {
+    /// acquire a connection and ping it, returns the round trip time
+    pub async fn ping(&self, timeout: Duration) -> Result<Duration, String> {
+        let start = Instant::now();
+        let ping = async {
+            let mut conn = self.pool.acquire().await?;
+            conn.ping().await
+        };
+        match tokio::time::timeout(timeout, ping).await {
+            Err(_) => Err(format!("no answer within {}ms", timeout.as_millis())),
+            Ok(Err(err)) => Err(err.to_string()),
+            Ok(Ok(_)) => {
+                self.state.store(AVAILABLE, Ordering::Relaxed);
+                Ok(start.elapsed())
+            }
+        }
+    }
+
+    /// retry connecting with exponential backoff, gives up after `max_attempts`
+    fn spawn_connect(self: &Arc<Self>) {
+        let db = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = Duration::from_millis(db.retry.initial_backoff_ms);
+            let max_backoff = Duration::from_millis(db.retry.max_backoff_ms);
+            for attempt in 1..=db.retry.max_attempts {
+                match db.pool.acquire().await {
+                    Ok(_) => {
+                        println!("db '{}' connected (attempt {})", db.name, attempt);
+                        db.state.store(AVAILABLE, Ordering::Relaxed);
+                        return;
+                    }
+                    Err(err) => {
+                        println!(
+                            "db '{}' not available (attempt {}): {}",
+                            db.name, attempt, err
+                        );
+                        if attempt < db.retry.max_attempts {
+                            tokio::time::sleep(backoff).await;
+                            backoff = (backoff * 2).min(max_backoff);
+                        }
+                    }
+                }
+            }
+            println!(
+                "db '{}' giving up for now, will retry on next request",
+                db.name
+            );
+            db.state.store(DOWN, Ordering::Relaxed);
+        });
+    }
+}
+
+/// one pool per configured database, looked up by name
+///
+/// pools are created lazily so the server can start before mysqld is ready
 pub struct Pools {
-    pub db: MySqlPool,
-    pub musker: MySqlPool,
-    pub svgbattle: MySqlPool,
+    databases: BTreeMap<String, Arc<Database>>,
+    pub ready_timeout: Duration,
+}
+
+impl Pools {
+    pub fn new(config: &Config) -> Result<Pools, sqlx::Error> {
+        let mut databases = BTreeMap::new();
+        for (name, database) in config.databases.iter() {
+            let pool = pool_options(database).connect_lazy(&database.url)?;
+            let db = Database {
+                name: name.clone(),
+                pool,
+                required: database.required,
+                limits: Limits {
+                    max_rows: database.max_rows,
+                    max_bytes: database.max_result_bytes,
+                },
+                query_timeout: database.query_timeout_ms.map(Duration::from_millis),
+                transaction_retry: database.transaction_retry.clone(),
+                state: AtomicU8::new(CONNECTING),
+                retry: config.connect_retry.clone(),
+            };
+            databases.insert(name.clone(), Arc::new(db));
+        }
+        Ok(Pools {
+            databases,
+            ready_timeout: Duration::from_millis(config.ready_timeout_ms),
+        })
+    }
+
+    /// start connecting to every database in the background
+    pub fn connect_in_background(&self) {
+        for db in self.databases.values() {
+            db.spawn_connect();
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<Database>> {
+        self.databases.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Database>> {
+        self.databases.values()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.databases.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+fn pool_options(database: &DatabaseConfig) -> MySqlPoolOptions {
+    let mut options = MySqlPoolOptions::new()
+        .max_connections(database.max_connections)
+        .min_connections(database.min_connections);
+    //only override sqlx defaults when configured, passing None would disable idle timeout and max lifetime
+    if let Some(secs) = database.acquire_timeout_secs {
+        options = options.acquire_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = database.idle_timeout_secs {
+        options = options.idle_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = database.max_lifetime_secs {
+        options = options.max_lifetime(Duration::from_secs(secs));
+    }
+    if let Some(test) = database.test_before_acquire {
+        options = options.test_before_acquire(test);
+    }
+    if !database.init_statements.is_empty() {
+        let init_statements = Arc::new(database.init_statements.clone());
+        options = options.after_connect(move |conn, _meta| {
+            let init_statements = init_statements.clone();
+            Box::pin(async move {
+                for sql in init_statements.iter() {
+                    conn.execute(sql.as_str()).await?;
+                }
+                Ok(())
+            })
+        });
+    }
+    options
 }
 
-pub fn select_pool_by_header(headermap: &HeaderMap, pools: &web::Data<Pools>) -> Option<MySqlPool> {
-    let custom_header: &'static str = "db";
-    let b = HeaderName::from_static(custom_header);
+/// the database name from the "db" header
+pub fn db_name(req: &HttpRequest) -> Result<&str, Error> {
     //default to "db" if no "db" header
-    let d = HeaderValue::from_str("db").unwrap();
-
-    let db = headermap.get(b).unwrap_or(&d).to_str().unwrap();
-    match db {
-        "db" => Some(pools.db.clone()),
-        "musker" => Some(pools.musker.clone()),
-        "svgbattle" => Some(pools.svgbattle.clone()),
-        _ => None,
+    match req.headers().get("db") {
+        None => Ok("db"),
+        Some(value) => value.to_str().map_err(|_| Error::BadClientData),
+    }
+}
+
+/// the pool named by the "db" header, if the authenticated user may use it
+pub fn select_pool_by_header(req: &HttpRequest, pools: &Pools) -> Result<MySqlPool, Error> {
+    let extensions = req.extensions();
+    let principal = extensions.get::<Principal>().ok_or(Error::Auth)?;
+    let db = db_name(req)?;
+    match pools.get(db) {
+        None => {
+            let names: Vec<&str> = pools
+                .names()
+                .into_iter()
+                .filter(|name| principal.may_use(name))
+                .collect();
+            Err(Error::UnknownDatabase(db.to_string(), names.join(", ")))
+        }
+        Some(db) if !principal.may_use(&db.name) => Err(Error::Forbidden(
+            principal.username.clone(),
+            db.name.clone(),
+        )),
+        Some(db) => match db.state.load(Ordering::Relaxed) {
+            AVAILABLE => Ok(db.pool.clone()),
+            state => {
+                if state == DOWN
+                    && db
+                        .state
+                        .compare_exchange(DOWN, CONNECTING, Ordering::Relaxed, Ordering::Relaxed)
+                        .is_ok()
+                {
+                    db.spawn_connect();
+                }
+                Err(Error::Unavailable(db.name.clone()))
+            }
+        },
+    }
+}
+
+/// what a request runs its queries on and with
+pub struct Selected {
+    pub pool: MySqlPool,
+    pub limits: Limits,
+    pub deadline: Deadline,
+    /// the request sent "query-timeout-ms", a stream is held to it until the end
+    /// instead of only until its first row
+    pub timeout_requested: bool,
+    pub transaction_retry: Option<RetryConfig>,
+}
+
+impl Selected {
+    /// a connection from the pool with the watchdog for its statements, give it back with `release()`.
+    /// for `read_only` it is inside `START TRANSACTION READ ONLY` so mysql itself refuses anything that writes
+    pub async fn acquire(&self, read_only: bool) -> Result<Acquired, Error> {
+        match read_only {
+            true => self.begin("START TRANSACTION READ ONLY").await,
+            false => self.acquire_in(None).await,
+        }
+    }
+
+    /// same as `acquire()` but inside a transaction started with `start`
+    pub async fn begin(&self, start: &str) -> Result<Acquired, Error> {
+        self.acquire_in(Some(start)).await
+    }
+
+    async fn acquire_in(&self, start: Option<&str>) -> Result<Acquired, Error> {
+        let mut conn = self.pool.acquire().await.map_err(|_| Error::NoConnection)?;
+        let watchdog = self.deadline.watchdog(&mut conn).await?;
+        if let Some(start) = start {
+            if let Err(err) = conn.execute(start).await {
+                //dont put a connection in an unknown state back in the pool
+                conn.detach();
+                return Err(Error::Transaction(
+                    "START TRANSACTION".to_string(),
+                    err.into(),
+                ));
+            }
+        }
+        Ok(Acquired {
+            conn,
+            watchdog,
+            in_transaction: start.is_some(),
+        })
+    }
+}
+
+/// a connection taken from the pool for one request, see `Selected::acquire`
+pub struct Acquired {
+    pub conn: PoolConnection<MySql>,
+    pub watchdog: Watchdog,
+    /// the transaction from `acquire()` is rolled back on `release()`
+    in_transaction: bool,
+}
+
+impl Acquired {
+    /// back to the pool, rolling back the read-only transaction. closed instead if the watchdog
+    /// gave up on a statement or the ROLLBACK failed, mysql rolls back when it goes away
+    pub async fn release(mut self) -> Result<(), Error> {
+        if self.watchdog.abandoned() {
+            self.conn.detach();
+            return Ok(());
+        }
+        if self.in_transaction {
+            if let Err(err) = self.conn.execute("ROLLBACK").await {
+                self.conn.detach();
+                return Err(Error::Transaction("ROLLBACK".to_string(), err.into()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `select_pool_by_header` plus the limits and deadline that apply
+pub fn select(req: &HttpRequest, pools: &Pools) -> Result<Selected, Error> {
+    let pool = select_pool_by_header(req, pools)?;
+    let limits = limits(req, pools)?;
+    let deadline = Deadline::new(pool.clone(), query_timeout(req, pools)?);
+    let transaction_retry = pools
+        .get(db_name(req)?)
+        .and_then(|db| db.transaction_retry.clone());
+    Ok(Selected {
+        pool,
+        limits,
+        deadline,
+        timeout_requested: req.headers().contains_key("query-timeout-ms"),
+        transaction_retry,
+    })
+}
+
+/// a number header parsed as `T`, an error naming the header if it does not parse
+fn header<T: FromStr>(req: &HttpRequest, name: &str) -> Result<Option<T>, Error> {
+    match req.headers().get(name) {
+        None => Ok(None),
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .map(Some)
+            .ok_or_else(|| Error::Request(format!("{} must be a number", name))),
+    }
+}
+
+/// the lowest of the database, principal and request limits,
+/// the request can lower them with the "max-rows" and "max-result-bytes" headers
+fn limits(req: &HttpRequest, pools: &Pools) -> Result<Limits, Error> {
+    let extensions = req.extensions();
+    let principal = extensions.get::<Principal>().ok_or(Error::Auth)?;
+    let database = pools
+        .get(db_name(req)?)
+        .map_or(Limits::default(), |db| db.limits);
+    let request = Limits {
+        max_rows: header(req, "max-rows")?,
+        max_bytes: header(req, "max-result-bytes")?,
+    };
+    Ok(database.min(principal.limits).min(request))
+}
+
+/// the lower of the database default and the "query-timeout-ms" header,
+/// a request can shorten the timeout but not extend it
+fn query_timeout(req: &HttpRequest, pools: &Pools) -> Result<Option<Duration>, Error> {
+    let database = pools.get(db_name(req)?).and_then(|db| db.query_timeout);
+    match header::<u64>(req, "query-timeout-ms")? {
+        None => Ok(database),
+        Some(0) => Err(Error::Request(
+            "query-timeout-ms must be more than 0".to_string(),
+        )),
+        Some(ms) => {
+            let request = Duration::from_millis(ms);
+            Ok(Some(
+                database.map_or(request, |database| database.min(request)),
+            ))
+        }
     }
 }
@@ -0,0 +1,65 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::config::Config;
+
+/// vetted sql per database, so less trusted clients can call queries by name instead of sending sql
+///
+/// every `<name>.sql` file in a database's `queries_dir` is a query named `<name>`,
+/// with `?` placeholders for the parameters like any other query
+pub struct Queries {
+    queries: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Queries {
+    pub fn load(config: &Config) -> Result<Queries, String> {
+        let mut queries = BTreeMap::new();
+        for (db, database) in config.databases.iter() {
+            if let Some(dir) = database.queries_dir.as_deref() {
+                queries.insert(db.clone(), read_dir(Path::new(dir))?);
+            }
+        }
+        Ok(Queries { queries })
+    }
+
+    pub fn get(&self, db: &str, name: &str) -> Option<&str> {
+        self.queries.get(db)?.get(name).map(|sql| sql.as_str())
+    }
+}
+
+fn read_dir(dir: &Path) -> Result<BTreeMap<String, String>, String> {
+    let entries = fs::read_dir(dir).map_err(|err| format!("could not read {:?}: {}", dir, err))?;
+    let mut queries = BTreeMap::new();
+    for entry in entries {
+        let path = entry.map_err(|err| err.to_string())?.path();
+        if path.extension().is_some_and(|ext| ext == "sql") {
+            if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                let sql = fs::read_to_string(&path)
+                    .map_err(|err| format!("could not read {:?}: {}", path, err))?;
+                queries.insert(name.to_string(), sql.trim().to_string());
+            }
+        }
+    }
+    Ok(queries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sql_files_become_named_queries() {
+        let dir = std::env::temp_dir().join(format!("queries-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("user_by_id.sql"),
+            "SELECT * FROM User WHERE id = ?\n",
+        )
+        .unwrap();
+        fs::write(dir.join("README.md"), "not a query").unwrap();
+
+        let queries = read_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries["user_by_id"], "SELECT * FROM User WHERE id = ?");
+    }
+}
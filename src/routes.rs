@@ -1,8 +1,25 @@
-use actix_web::{get, post, web, HttpResponse, Responder};
-use serde::Deserialize;
+use actix_web::{
+    get,
+    http::{
+        header::{HeaderName, HeaderValue},
+        StatusCode,
+    },
+    post, web, HttpResponse, Responder, ResponseError,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use sqlx::Executor;
+use sqlx_mysql_json::{error::Class, Limits};
+use std::time::Duration;
 
-use crate::pools::{select_pool_by_header, Pools};
+use crate::{
+    auth::Principal,
+    config::RetryConfig,
+    deadline::Watchdog,
+    error::Error,
+    pools::{db_name, select, Pools, Selected},
+    queries::Queries,
+};
 
 #[derive(Deserialize)]
 struct Q {
@@ -13,76 +30,758 @@ struct Q {
 async fn root(
     pools: web::Data<Pools>,
     req: actix_web::HttpRequest,
+    principal: web::ReqData<Principal>,
     query: web::Query<Q>,
 ) -> impl Responder {
-    match select_pool_by_header(req.headers(), &pools) {
-        None => HttpResponse::BadRequest().json("bad db header".to_string()),
-        Some(pool) => {
-            //general purpose "query via http"
-            let result = sqlx_mysql_json::query(&pool, &query.q).await;
-            match result {
-                Ok(value) => HttpResponse::Ok().json(value),
-                Err(err) => HttpResponse::BadRequest().json(err.to_string()),
-            }
+    if let Err(err) = principal.may_send_sql() {
+        return err.error_response();
+    }
+    match select(&req, &pools) {
+        Err(err) => err.error_response(),
+        Ok(selected) => run_query(&principal, &selected, &query.q).await,
+    }
+}
+
+#[derive(Deserialize)]
+struct QueryParams {
+    /// respond with one line of json per row instead of one big json object
+    #[serde(default)]
+    stream: bool,
+}
+
+/// same as `GET /?q=...` but with the query json as body, for big parameters
+///
+/// with `?stream=true` a SELECT responds with newline delimited json as rows arrive, see `stream_query`
+#[post("/query")]
+async fn post_query(
+    pools: web::Data<Pools>,
+    req: actix_web::HttpRequest,
+    principal: web::ReqData<Principal>,
+    params: web::Query<QueryParams>,
+    query: web::Json<serde_json::Value>,
+) -> impl Responder {
+    if let Err(err) = principal.may_send_sql() {
+        return err.error_response();
+    }
+    match select(&req, &pools) {
+        Err(err) => err.error_response(),
+        Ok(selected) if params.stream => {
+            stream_query(&principal, &selected, query.to_string()).await
         }
+        Ok(selected) => run_query(&principal, &selected, &query.to_string()).await,
     }
 }
 
-#[post("/transaction")]
-pub async fn transaction(
+/// call a query from the registry by name, the body is the parameters array
+#[post("/q/{name}")]
+async fn named_query(
+    pools: web::Data<Pools>,
+    queries: web::Data<Queries>,
+    req: actix_web::HttpRequest,
+    principal: web::ReqData<Principal>,
+    name: web::Path<String>,
+    parameters: web::Json<Vec<serde_json::Value>>,
+) -> impl Responder {
+    let selected = match select(&req, &pools) {
+        Err(err) => return err.error_response(),
+        Ok(selected) => selected,
+    };
+    let sql = db_name(&req).ok().and_then(|db| queries.get(db, &name));
+    match sql {
+        None => {
+            let err = Error::UnknownQuery(name.into_inner());
+            err.error_response()
+        }
+        Some(sql) => {
+            let q = serde_json::json!({ "sql": sql, "parameters": parameters.into_inner() });
+            run_query(&principal, &selected, &q.to_string()).await
+        }
+    }
+}
+
+/// general purpose "query via http"
+///
+/// read-only keys may only send a SELECT, it runs inside `START TRANSACTION READ ONLY`
+/// so mysql itself refuses anything that writes, eg functions with side effects or `SELECT ... INTO OUTFILE`
+async fn run_query(principal: &Principal, selected: &Selected, q: &str) -> HttpResponse {
+    if principal.read_only && !sqlx_mysql_json::is_select(q) {
+        let err = Error::ReadOnly(principal.username.clone());
+        return err.error_response();
+    }
+    let mut acquired = match selected.acquire(principal.read_only).await {
+        Err(err) => return err.error_response(),
+        Ok(acquired) => acquired,
+    };
+    let result = acquired
+        .watchdog
+        .run(sqlx_mysql_json::query_limited(
+            &mut *acquired.conn,
+            q,
+            selected.limits,
+        ))
+        .await;
+    if let Err(err) = acquired.release().await {
+        return err.error_response();
+    }
+    match result {
+        Err(err) => err.error_response(),
+        Ok(Ok(value)) => HttpResponse::Ok().json(value),
+        Ok(Err(err)) => Error::from(err).error_response(),
+    }
+}
+
+/// one json value per line
+fn ndjson_line(value: &serde_json::Value) -> web::Bytes {
+    let mut line = serde_json::to_vec(value).unwrap_or_default();
+    line.push(b'\n');
+    web::Bytes::from(line)
+}
+
+/// send rows as `application/x-ndjson` while mysql is still producing them,
+/// the last line is a summary `{ "numRows": 1000 }` or `{ "numRows": 10, "error": {...} }`
+/// since the 200 status is long gone if something fails halfway,
+/// `"truncated": true` in the summary means `limits` stopped it early
+///
+/// exports are expected to take a while, so the query timeout only lasts until the first row
+/// unless the request asked for one with the "query-timeout-ms" header
+async fn stream_query(principal: &Principal, selected: &Selected, q: String) -> HttpResponse {
+    if !sqlx_mysql_json::is_select(&q) {
+        return Error::NotSelect.error_response();
+    }
+    let limits = selected.limits;
+    let whole_stream = selected.timeout_requested;
+    let mut acquired = match selected.acquire(principal.read_only).await {
+        Err(err) => return err.error_response(),
+        Ok(acquired) => acquired,
+    };
+
+    //bounded so a slow client slows down reading from mysql instead of buffering everything
+    let (tx, rx) = tokio::sync::mpsc::channel::<web::Bytes>(64);
+    tokio::spawn(async move {
+        let mut num_rows: u64 = 0;
+        let mut num_bytes = 0;
+        let mut truncated = false;
+        let watchdog = &acquired.watchdog;
+        let fetch = sqlx_mysql_json::fetch_each(&mut *acquired.conn, &q, |row| {
+            if !whole_stream {
+                watchdog.disarm();
+            }
+            let line = ndjson_line(&row);
+            let exceeded = limits.exceeded_by(num_rows, num_bytes, line.len());
+            if exceeded {
+                truncated = true;
+            } else {
+                num_rows += 1;
+                num_bytes += line.len();
+            }
+            let tx = tx.clone();
+            async move { !exceeded && tx.send(line).await.is_ok() }
+        });
+        let result = match watchdog.run(fetch).await {
+            Err(err) => Err(err),
+            Ok(result) => result.map_err(Error::from),
+        };
+        let mut summary = match result {
+            Ok(_) => serde_json::json!({ "numRows": num_rows }),
+            Err(err) => serde_json::json!({ "numRows": num_rows, "error": err.to_value() }),
+        };
+        if truncated {
+            summary["truncated"] = serde_json::json!(true);
+        }
+        let _ = tx.send(ndjson_line(&summary)).await;
+        let _ = acquired.release().await;
+    });
+
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|line| (Ok::<_, actix_web::Error>(line), rx))
+    });
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(body)
+}
+
+/// independent queries on one connection, one failing does not stop the others
+///
+/// returns `{ "result": ... }` or `{ "error": {...} }` per query
+#[post("/batch")]
+pub async fn batch(
     pools: web::Data<Pools>,
     req: actix_web::HttpRequest,
+    principal: web::ReqData<Principal>,
     queries: web::Json<Vec<String>>,
 ) -> impl Responder {
+    if let Err(err) = principal.may_send_sql() {
+        return err.error_response();
+    }
+    let selected = match select(&req, &pools) {
+        Err(err) => return err.error_response(),
+        Ok(selected) => selected,
+    };
+    let mut acquired = match selected.acquire(principal.read_only).await {
+        Err(err) => return err.error_response(),
+        Ok(acquired) => acquired,
+    };
+
     let mut results: Vec<serde_json::Value> = vec![];
+    for q in queries.iter() {
+        let result = if principal.read_only && !sqlx_mysql_json::is_select(q) {
+            Err(Error::ReadOnly(principal.username.clone()))
+        } else {
+            let query = sqlx_mysql_json::query_limited(&mut *acquired.conn, q, selected.limits);
+            match acquired.watchdog.run(query).await {
+                Err(err) => Err(err),
+                Ok(result) => result.map_err(Error::from),
+            }
+        };
+        results.push(match result {
+            Ok(value) => serde_json::json!({ "result": value }),
+            Err(err) => serde_json::json!({ "error": err.to_value() }),
+        });
+    }
+
+    if let Err(err) = acquired.release().await {
+        return err.error_response();
+    }
+    HttpResponse::Ok().json(results)
+}
+
+/// an item in the `/transaction` body, a query json string or a block of them
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Statement {
+    Query(String),
+    /// `{ "optional": [...] }` runs inside a savepoint, if one of them fails
+    /// the block is rolled back to the savepoint and the transaction continues
+    Optional {
+        optional: Vec<String>,
+    },
+    /// `{ "query": "...", "expect": { "numAffectedRows": 1 } }` rolls back everything
+    /// unless the query affected (or for SELECT returned, `numRows`) that many rows
+    Checked {
+        query: String,
+        expect: Expect,
+    },
+}
 
-    match select_pool_by_header(req.headers(), &pools) {
-        None => HttpResponse::BadRequest().json("bad db header".to_string()),
-        Some(pool) => {
-            match pool.acquire().await {
-                Err(_) => HttpResponse::InternalServerError()
-                    .json("couldnt acquire connection from pool".to_string()),
-                Ok(mut conn) => {
-                    //let _ = conn.execute("SET autocommit=1").await; //default.. except when inside a START TRANSACTION?...
-                    //let _ = conn.execute("SET autocommit=0").await;
-                    //
-                    match conn.execute("START TRANSACTION").await {
-                        Err(_) => {
-                            HttpResponse::InternalServerError().json("failed to START TRANSACTION")
-                        }
-                        Ok(_) => {
-                            let mut should_rollback = false;
-                            for q in queries.into_inner() {
-                                match sqlx_mysql_json::execute_in_connection(&mut conn, &q).await {
-                                    Ok(result) => {
-                                        results.push(result);
-                                    }
-                                    Err(_) => {
-                                        should_rollback = true;
-                                        break;
-                                    }
-                                }
-                            }
-
-                            match should_rollback {
-                                true => match conn.execute("ROLLBACK").await {
-                                    Ok(_) => HttpResponse::BadRequest().json("ROLLBACK"),
-                                    Err(_) => HttpResponse::InternalServerError()
-                                        .json("failed to ROLLBACK"),
-                                },
-
-                                false => match conn.execute("COMMIT").await {
-                                    Ok(_) => HttpResponse::Ok().json(results),
-                                    Err(_) => {
-                                        HttpResponse::InternalServerError().json("failed to COMMIT")
-                                    }
-                                },
-                            }
-                        }
-                    }
+/// an exact number of rows or `{ "min": 1, "max": 10 }`, at least one of them
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Count {
+    Exactly(u64),
+    Range { min: Option<u64>, max: Option<u64> },
+}
+
+impl Count {
+    fn matches(&self, n: u64) -> bool {
+        match self {
+            Count::Exactly(expected) => n == *expected,
+            Count::Range { min, max } => min.unwrap_or(0) <= n && n <= max.unwrap_or(u64::MAX),
+        }
+    }
+}
+
+/// a typo must not turn into an assertion that always passes,
+/// so unknown fields, no assertion at all and empty ranges are rejected
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "ExpectJson")]
+pub struct Expect {
+    num_affected_rows: Option<Count>,
+    num_rows: Option<Count>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct ExpectJson {
+    num_affected_rows: Option<Count>,
+    num_rows: Option<Count>,
+}
+
+impl TryFrom<ExpectJson> for Expect {
+    type Error = String;
+
+    fn try_from(json: ExpectJson) -> Result<Expect, String> {
+        let counts = [&json.num_affected_rows, &json.num_rows];
+        if counts.iter().all(|count| count.is_none()) {
+            return Err("expect needs numAffectedRows or numRows".to_string());
+        }
+        let empty_range = |count: &&Option<Count>| {
+            matches!(
+                count,
+                Some(Count::Range {
+                    min: None,
+                    max: None
+                })
+            )
+        };
+        if counts.iter().any(empty_range) {
+            return Err("expect ranges need min or max".to_string());
+        }
+        Ok(Expect {
+            num_affected_rows: json.num_affected_rows,
+            num_rows: json.num_rows,
+        })
+    }
+}
+
+impl Expect {
+    /// the failed assertion as the usual error object with `"kind": "expectation"`
+    /// and `"assertion"`, `"expected"` and `"actual"` on top
+    fn check(&self, result: &serde_json::Value) -> Result<(), serde_json::Value> {
+        //numAffectedRows is ["BigInt", "1"]
+        let num_affected_rows = result["numAffectedRows"][1]
+            .as_str()
+            .and_then(|n| n.parse::<u64>().ok())
+            .unwrap_or(0);
+        let num_rows = result["rows"]
+            .as_array()
+            .map_or(0, |rows| rows.len() as u64);
+        let assertions = [
+            (
+                "numAffectedRows",
+                &self.num_affected_rows,
+                num_affected_rows,
+            ),
+            ("numRows", &self.num_rows, num_rows),
+        ];
+        //the real number of rows is unknown past a cap, so the assertion can neither pass nor fail
+        if let (Some(expected), Some(true)) = (&self.num_rows, result["truncated"].as_bool()) {
+            let message = format!(
+                "expected numRows {} but the result was truncated at {} rows by max-rows or max-result-bytes",
+                serde_json::to_string(expected).unwrap_or_default(),
+                num_rows
+            );
+            let mut error = Error::Expectation(message).to_value();
+            error["assertion"] = "numRows".into();
+            error["expected"] = serde_json::json!(expected);
+            error["actual"] = serde_json::Value::Null;
+            return Err(error);
+        }
+        for (assertion, expected, actual) in assertions {
+            if let Some(expected) = expected {
+                if !expected.matches(actual) {
+                    let message = format!(
+                        "expected {} {}, got {}",
+                        assertion,
+                        serde_json::to_string(expected).unwrap_or_default(),
+                        actual
+                    );
+                    let mut error = Error::Expectation(message).to_value();
+                    error["assertion"] = assertion.into();
+                    error["expected"] = serde_json::json!(expected);
+                    error["actual"] = actual.into();
+                    return Err(error);
                 }
             }
         }
+        Ok(())
+    }
+}
+
+impl Statement {
+    fn queries(&self) -> &[String] {
+        match self {
+            Statement::Query(q) => std::slice::from_ref(q),
+            Statement::Optional { optional } => optional,
+            Statement::Checked { query, .. } => std::slice::from_ref(query),
+        }
+    }
+}
+
+/// the index of the first statement that would commit the transaction halfway (DDL, COMMIT, ...),
+/// what came before it could neither be rolled back nor safely replayed after a deadlock
+fn ends_transaction(statements: &[Statement]) -> Option<usize> {
+    statements.iter().position(|statement| {
+        statement
+            .queries()
+            .iter()
+            .any(|q| sqlx_mysql_json::ends_transaction(q))
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub enum IsolationLevel {
+    #[serde(rename = "READ COMMITTED")]
+    ReadCommitted,
+    #[serde(rename = "REPEATABLE READ")]
+    RepeatableRead,
+    #[serde(rename = "SERIALIZABLE")]
+    Serializable,
+}
+
+/// how to start the transaction, mysql defaults for anything not set
+///
+/// a typo must not quietly run at the default isolation level, so unknown fields are rejected
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TransactionOptions {
+    isolation_level: Option<IsolationLevel>,
+    /// `READ ONLY` or `READ WRITE`, always read only for read-only keys
+    read_only: Option<bool>,
+    /// `START TRANSACTION WITH CONSISTENT SNAPSHOT`, for several SELECTs seeing the same data
+    #[serde(default)]
+    consistent_snapshot: bool,
+    statements: Vec<Statement>,
+}
+
+impl TransactionOptions {
+    /// the `SET TRANSACTION` (if any) and `START TRANSACTION` statements,
+    /// None if a read-only key asks for `READ WRITE`
+    fn sql(&self, read_only_key: bool) -> Option<(Option<String>, String)> {
+        let read_only = match (read_only_key, self.read_only) {
+            (true, Some(false)) => return None,
+            (true, _) => Some(true),
+            (false, read_only) => read_only,
+        };
+        let mut characteristics = vec![];
+        if let Some(level) = &self.isolation_level {
+            characteristics.push(match level {
+                IsolationLevel::ReadCommitted => "ISOLATION LEVEL READ COMMITTED",
+                IsolationLevel::RepeatableRead => "ISOLATION LEVEL REPEATABLE READ",
+                IsolationLevel::Serializable => "ISOLATION LEVEL SERIALIZABLE",
+            });
+        }
+        match read_only {
+            Some(true) => characteristics.push("READ ONLY"),
+            Some(false) => characteristics.push("READ WRITE"),
+            None => {}
+        }
+        //SET TRANSACTION without GLOBAL or SESSION only applies to the next transaction
+        let set = match characteristics.is_empty() {
+            true => None,
+            false => Some(format!("SET TRANSACTION {}", characteristics.join(", "))),
+        };
+        let start = match self.consistent_snapshot {
+            true => "START TRANSACTION WITH CONSISTENT SNAPSHOT",
+            false => "START TRANSACTION",
+        };
+        Some((set, start.to_string()))
+    }
+}
+
+/// the `/transaction` body, a plain array of statements or
+/// `{ "isolationLevel": "SERIALIZABLE", "readOnly": true, "consistentSnapshot": true, "statements": [...] }`
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum TransactionBody {
+    Statements(Vec<Statement>),
+    WithOptions(TransactionOptions),
+}
+
+/// run the queries of the optional block at `index` inside a savepoint,
+/// a failing query is reported in the result as the error and the index inside the block
+///
+/// `earlier` are the results of the statements before the block, for `["Ref", index, key]` parameters
+async fn run_optional(
+    conn: &mut sqlx::MySqlConnection,
+    index: usize,
+    queries: &[String],
+    earlier: &[serde_json::Value],
+    limits: Limits,
+    watchdog: &Watchdog,
+) -> Result<serde_json::Value, Failure> {
+    let savepoint = format!("optional_{}", index);
+    savepoint_sql(&mut *conn, index, format!("SAVEPOINT {}", savepoint)).await?;
+    let mut results: Vec<serde_json::Value> = vec![];
+    for (inner, q) in queries.iter().enumerate() {
+        let query = sqlx_mysql_json::query_with_results(&mut *conn, q, earlier, limits);
+        match watchdog
+            .run(query)
+            .await
+            .map_err(|err| Failure::error(index, err))?
+        {
+            Ok(result) => results.push(result),
+            //a deadlock rolled back the whole transaction, not just the block,
+            //and either way the whole transaction can be replayed
+            Err(err) if err.class() == Class::Retryable => return Err(Failure::mysql(index, err)),
+            Err(err) => {
+                savepoint_sql(
+                    &mut *conn,
+                    index,
+                    format!("ROLLBACK TO SAVEPOINT {}", savepoint),
+                )
+                .await?;
+                return Ok(serde_json::json!({
+                    "error": err.to_value(),
+                    "index": inner,
+                }));
+            }
+        }
+    }
+    savepoint_sql(
+        &mut *conn,
+        index,
+        format!("RELEASE SAVEPOINT {}", savepoint),
+    )
+    .await?;
+    Ok(serde_json::json!({ "results": results }))
+}
+
+/// `SAVEPOINT` and friends for the optional block at `index`, failing rolls back everything
+async fn savepoint_sql(
+    conn: &mut sqlx::MySqlConnection,
+    index: usize,
+    sql: String,
+) -> Result<(), Failure> {
+    match conn.execute(sql.as_str()).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Failure::mysql(index, err.into())),
+    }
+}
+
+/// why a `/transaction` is rolled back
+struct Failure {
+    /// of the statement in the request
+    index: usize,
+    /// the status of the mysql error (see `Error::status_code`), 409 for a failed expectation, 504 for a timeout
+    status: StatusCode,
+    error: serde_json::Value,
+    /// a deadlock or lock wait timeout, replaying the transaction might work
+    retryable: bool,
+}
+
+impl Failure {
+    fn mysql(index: usize, err: sqlx_mysql_json::error::Error) -> Failure {
+        Failure::error(index, err.into())
+    }
+
+    fn error(index: usize, err: Error) -> Failure {
+        Failure {
+            index,
+            status: err.status_code(),
+            retryable: matches!(&err, Error::Query(err) if err.class() == Class::Retryable),
+            error: err.to_value(),
+        }
+    }
+}
+
+/// run `statements` in order, pushing their results to `results`, until one fails
+async fn run_statements(
+    conn: &mut sqlx::MySqlConnection,
+    statements: &[Statement],
+    results: &mut Vec<serde_json::Value>,
+    limits: Limits,
+    watchdog: &Watchdog,
+) -> Result<(), Failure> {
+    for (index, statement) in statements.iter().enumerate() {
+        match statement {
+            //SELECT (including SELECT ... FOR UPDATE) returns rows, anything else numAffectedRows etc
+            Statement::Query(q) => {
+                let query = sqlx_mysql_json::query_with_results(&mut *conn, q, results, limits);
+                let result = watchdog
+                    .run(query)
+                    .await
+                    .map_err(|err| Failure::error(index, err))?
+                    .map_err(|err| Failure::mysql(index, err))?;
+                results.push(result);
+            }
+            //a failing optional block is reported in its result, not as a failure
+            Statement::Optional { optional } => {
+                let result =
+                    run_optional(&mut *conn, index, optional, results, limits, watchdog).await?;
+                results.push(result);
+            }
+            Statement::Checked { query, expect } => {
+                let query = sqlx_mysql_json::query_with_results(&mut *conn, query, results, limits);
+                let result = watchdog
+                    .run(query)
+                    .await
+                    .map_err(|err| Failure::error(index, err))?
+                    .map_err(|err| Failure::mysql(index, err))?;
+                expect.check(&result).map_err(|error| Failure {
+                    index,
+                    status: StatusCode::CONFLICT,
+                    error,
+                    retryable: false,
+                })?;
+                results.push(result);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[post("/transaction")]
+pub async fn transaction(
+    pools: web::Data<Pools>,
+    req: actix_web::HttpRequest,
+    principal: web::ReqData<Principal>,
+    body: web::Json<TransactionBody>,
+) -> impl Responder {
+    let options = match body.into_inner() {
+        TransactionBody::Statements(statements) => TransactionOptions {
+            statements,
+            ..TransactionOptions::default()
+        },
+        TransactionBody::WithOptions(options) => options,
+    };
+    let statements = &options.statements;
+
+    if let Err(err) = principal.may_send_sql() {
+        return err.error_response();
+    }
+    if principal.read_only
+        && !statements
+            .iter()
+            .flat_map(|statement| statement.queries())
+            .all(|q| sqlx_mysql_json::is_select(q))
+    {
+        let err = Error::ReadOnly(principal.username.clone());
+        return err.error_response();
+    }
+    if let Some(index) = ends_transaction(statements) {
+        let err = Error::EndsTransaction;
+        return HttpResponse::build(err.status_code()).json(serde_json::json!({
+            "error": err.to_value(),
+            "index": index,
+            "results": [],
+        }));
+    }
+    let (set, start) = match options.sql(principal.read_only) {
+        None => {
+            let err = Error::ReadOnly(principal.username.clone());
+            return err.error_response();
+        }
+        Some(sql) => sql,
+    };
+
+    let selected = match select(&req, &pools) {
+        Err(err) => return err.error_response(),
+        Ok(selected) => selected,
+    };
+    //let _ = conn.execute("SET autocommit=1").await; //default.. except when inside a START TRANSACTION?...
+    //let _ = conn.execute("SET autocommit=0").await;
+    //
+    let mut acquired = match selected.acquire(false).await {
+        Err(err) => return err.error_response(),
+        Ok(acquired) => acquired,
+    };
+
+    let mut backoff = Backoff::new(selected.transaction_retry.as_ref());
+    let mut attempts: u32 = 0;
+    let mut close = false;
+    let mut response = loop {
+        attempts += 1;
+        let mut results: Vec<serde_json::Value> = vec![];
+        let outcome = run_transaction(
+            &mut acquired.conn,
+            set.as_deref(),
+            &start,
+            statements,
+            &mut results,
+            selected.limits,
+            &acquired.watchdog,
+        )
+        .await;
+        match outcome {
+            //eg SET TRANSACTION worked but START TRANSACTION did not, its characteristics would
+            //apply to the next transaction on this connection, so it is closed instead of reused
+            Err(err) => {
+                close = true;
+                break err.error_response();
+            }
+            Ok(Ok(())) => break HttpResponse::Ok().json(results),
+            Ok(Err(failure)) if failure.retryable && attempts < backoff.max_attempts => {
+                backoff.sleep().await;
+            }
+            //results of the statements before the failing one are rolled back
+            //but still useful to see what happened
+            Ok(Err(failure)) => {
+                break HttpResponse::build(failure.status).json(serde_json::json!({
+                    "error": failure.error,
+                    "index": failure.index,
+                    "results": results,
+                    "attempts": attempts,
+                }))
+            }
+        }
+    };
+    if close {
+        drop(acquired.conn.detach());
+    } else {
+        let _ = acquired.release().await;
+    }
+    response.headers_mut().insert(
+        HeaderName::from_static("transaction-attempts"),
+        HeaderValue::from(attempts),
+    );
+    response
+}
+
+/// one attempt at a `/transaction`, the inner error is a failed statement after a successful ROLLBACK,
+/// the outer error leaves the connection in an unknown state
+async fn run_transaction(
+    conn: &mut sqlx::MySqlConnection,
+    set: Option<&str>,
+    start: &str,
+    statements: &[Statement],
+    results: &mut Vec<serde_json::Value>,
+    limits: Limits,
+    watchdog: &Watchdog,
+) -> Result<Result<(), Failure>, Error> {
+    if let Some(set) = set {
+        if let Err(err) = conn.execute(set).await {
+            return Err(Error::Transaction(
+                "SET TRANSACTION".to_string(),
+                err.into(),
+            ));
+        }
+    }
+    if let Err(err) = conn.execute(start).await {
+        return Err(Error::Transaction(
+            "START TRANSACTION".to_string(),
+            err.into(),
+        ));
+    }
+    match run_statements(&mut *conn, statements, results, limits, watchdog).await {
+        //the connection is closed without a ROLLBACK, mysql rolls back when it goes away
+        Err(failure) if watchdog.abandoned() => Ok(Err(failure)),
+        Err(failure) => match conn.execute("ROLLBACK").await {
+            Ok(_) => Ok(Err(failure)),
+            Err(err) => Err(Error::Transaction("ROLLBACK".to_string(), err.into())),
+        },
+        Ok(()) => match conn.execute("COMMIT").await {
+            Ok(_) => Ok(Ok(())),
+            Err(err) => Err(Error::Transaction("COMMIT".to_string(), err.into())),
+        },
+    }
+}
+
+/// exponential backoff between attempts, without a `RetryConfig` there is only one attempt
+struct Backoff {
+    max_attempts: u32,
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn new(retry: Option<&RetryConfig>) -> Backoff {
+        match retry {
+            None => Backoff {
+                max_attempts: 1,
+                next: Duration::ZERO,
+                max: Duration::ZERO,
+            },
+            Some(retry) => Backoff {
+                max_attempts: retry.max_attempts.max(1),
+                next: Duration::from_millis(retry.initial_backoff_ms),
+                max: Duration::from_millis(retry.max_backoff_ms),
+            },
+        }
+    }
+
+    /// how long to wait before the next attempt, somewhere between half and all of the current backoff
+    /// so transactions that deadlocked on each other dont retry in lockstep and deadlock again.
+    /// the backoff doubles (up to `max`) for the attempt after that
+    fn next_delay(&mut self) -> Duration {
+        let ms = self.next.as_millis() as u64;
+        self.next = (self.next * 2).min(self.max);
+        Duration::from_millis(rand::thread_rng().gen_range(ms / 2..=ms))
+    }
+
+    async fn sleep(&mut self) {
+        tokio::time::sleep(self.next_delay()).await;
     }
 }
 
@@ -113,3 +812,136 @@ pub async fn examples(pool: web::Data<MySqlPool>) -> impl Responder {
     }
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transaction_body_mixes_queries_and_optional_blocks() {
+        let s = r#"[
+            "{\"sql\": \"INSERT INTO Import (batch) VALUES (?)\", \"parameters\": [1]}",
+            { "optional": ["{\"sql\": \"INSERT INTO Row (id) VALUES (1)\"}"] },
+            { "optional": [] }
+        ]"#;
+        let statements: Vec<Statement> = serde_json::from_str(s).unwrap();
+        assert!(matches!(statements[0], Statement::Query(_)));
+        assert_eq!(statements[1].queries().len(), 1);
+        assert!(statements[2].queries().is_empty());
+    }
+
+    #[test]
+    fn statements_that_would_commit_halfway_are_found() {
+        let s = r#"[
+            "{\"sql\": \"INSERT INTO Import (batch) VALUES (1)\", \"parameters\": []}",
+            { "optional": ["{\"sql\": \"SELECT 1\", \"parameters\": []}"] },
+            { "optional": ["{\"sql\": \"CREATE TABLE Copy LIKE Import\", \"parameters\": []}"] },
+            "{\"sql\": \"COMMIT\", \"parameters\": []}"
+        ]"#;
+        let statements: Vec<Statement> = serde_json::from_str(s).unwrap();
+        assert_eq!(ends_transaction(&statements), Some(2));
+        assert_eq!(ends_transaction(&statements[..2]), None);
+        assert_eq!(ends_transaction(&statements[3..]), Some(0));
+    }
+
+    #[test]
+    fn transaction_options_become_set_transaction() {
+        let s =
+            r#"{"isolationLevel": "SERIALIZABLE", "consistentSnapshot": true, "statements": []}"#;
+        let TransactionBody::WithOptions(options) = serde_json::from_str(s).unwrap() else {
+            panic!("expected options");
+        };
+        let (set, start) = options.sql(true).unwrap();
+        assert_eq!(
+            set.as_deref(),
+            Some("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE, READ ONLY")
+        );
+        assert_eq!(start, "START TRANSACTION WITH CONSISTENT SNAPSHOT");
+
+        let (set, start) = TransactionOptions::default().sql(false).unwrap();
+        assert!(set.is_none());
+        assert_eq!(start, "START TRANSACTION");
+
+        let s = r#"{"readOnly": false, "statements": []}"#;
+        let TransactionBody::WithOptions(options) = serde_json::from_str(s).unwrap() else {
+            panic!("expected options");
+        };
+        assert!(options.sql(true).is_none());
+
+        for typo in [
+            r#"{"isolation": "SERIALIZABLE", "statements": []}"#,
+            r#"{"consistentSnaphot": true, "statements": []}"#,
+            r#"{"isolationLevel": "SERIALIZABLE"}"#,
+        ] {
+            assert!(
+                serde_json::from_str::<TransactionBody>(typo).is_err(),
+                "{}",
+                typo
+            );
+        }
+    }
+
+    #[test]
+    fn expectations_check_row_counts() {
+        let s = r#"[
+            { "query": "{\"sql\": \"UPDATE Post SET version = version + 1 WHERE id = 1 AND version = 3\"}", "expect": { "numAffectedRows": 1 } },
+            { "query": "{\"sql\": \"SELECT * FROM Post\"}", "expect": { "numRows": { "min": 1 } } }
+        ]"#;
+        let statements: Vec<Statement> = serde_json::from_str(s).unwrap();
+        let Statement::Checked { expect, .. } = &statements[0] else {
+            panic!("expected a checked statement");
+        };
+        let updated = serde_json::json!({ "numAffectedRows": ["BigInt", "1"], "rows": [] });
+        let stale = serde_json::json!({ "numAffectedRows": ["BigInt", "0"], "rows": [] });
+        assert!(expect.check(&updated).is_ok());
+        let err = expect.check(&stale).unwrap_err();
+        assert_eq!(err["assertion"], "numAffectedRows");
+        assert_eq!(err["actual"], 0);
+        assert_eq!(err["kind"], "expectation");
+        assert_eq!(err["retryable"], false);
+        assert!(err["code"].is_null());
+
+        let Statement::Checked { expect, .. } = &statements[1] else {
+            panic!("expected a checked statement");
+        };
+        assert!(expect
+            .check(&serde_json::json!({ "rows": [{}, {}] }))
+            .is_ok());
+        assert!(expect.check(&serde_json::json!({ "rows": [] })).is_err());
+        let truncated = serde_json::json!({ "rows": [{}, {}], "truncated": true });
+        let err = expect.check(&truncated).unwrap_err();
+        assert_eq!(err["assertion"], "numRows");
+        assert!(err["actual"].is_null());
+        for typo in [
+            r#"{ "numAffectedRow": 1 }"#,
+            r#"{ "numRows": { "mn": 1 } }"#,
+            r#"{ "numRows": { "min": 1, "mx": 5 } }"#,
+            r#"{ "numRows": {} }"#,
+            r#"{}"#,
+        ] {
+            assert!(serde_json::from_str::<Expect>(typo).is_err(), "{}", typo);
+        }
+    }
+
+    #[test]
+    fn backoff_is_jittered_and_capped() {
+        assert_eq!(Backoff::new(None).max_attempts, 1);
+        let retry = RetryConfig {
+            max_attempts: 3,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 150,
+        };
+        assert_eq!(Backoff::new(Some(&retry)).max_attempts, 3);
+        let ms = Duration::from_millis;
+        for _ in 0..20 {
+            let mut backoff = Backoff::new(Some(&retry));
+            let first = backoff.next_delay();
+            assert!(first >= ms(50) && first <= ms(100));
+            //doubled to 200 but capped at 150
+            let second = backoff.next_delay();
+            assert!(second >= ms(75) && second <= ms(150));
+            let third = backoff.next_delay();
+            assert!(third >= ms(75) && third <= ms(150));
+        }
+    }
+}
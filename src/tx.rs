@@ -0,0 +1,296 @@
+use actix_web::{post, web, HttpResponse, Responder, ResponseError};
+use sqlx::{pool::PoolConnection, Executor, MySql};
+use sqlx_mysql_json::{error::Class, Limits};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth::Principal,
+    deadline::Watchdog,
+    error::Error,
+    pools::{db_name, select, Acquired, Pools},
+};
+
+/// a transaction kept open between requests, on a connection taken out of the pool
+struct HeldTx {
+    /// taken when the transaction ends, so a request that was waiting on the lock sees it is gone
+    conn: Option<PoolConnection<MySql>>,
+    db: String,
+    read_only: bool,
+    /// from the request that started the transaction
+    limits: Limits,
+    watchdog: Watchdog,
+    last_used: Instant,
+}
+
+struct Entry {
+    username: String,
+    tx: Arc<tokio::sync::Mutex<HeldTx>>,
+}
+
+/// COMMIT or ROLLBACK, a connection that fails to end its transaction is closed instead of returned to the pool
+async fn finish(mut conn: PoolConnection<MySql>, sql: &str) -> Result<(), sqlx::Error> {
+    match conn.execute(sql).await {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            conn.detach();
+            Err(err)
+        }
+    }
+}
+
+/// open interactive transactions by id
+///
+/// the client logic between statements runs in the client:
+/// `POST /tx` to start, `POST /tx/{id}` with statements as many times as needed,
+/// then `POST /tx/{id}/commit` or `POST /tx/{id}/rollback`
+pub struct Transactions {
+    held: Mutex<HashMap<String, Entry>>,
+    idle_timeout: Duration,
+}
+
+impl Transactions {
+    pub fn new(idle_timeout: Duration) -> Transactions {
+        Transactions {
+            held: Mutex::new(HashMap::new()),
+            idle_timeout,
+        }
+    }
+
+    fn insert(&self, username: String, tx: HeldTx) -> String {
+        let id = uuid::Uuid::new_v4().to_string();
+        let tx = Arc::new(tokio::sync::Mutex::new(tx));
+        self.held
+            .lock()
+            .unwrap()
+            .insert(id.clone(), Entry { username, tx });
+        id
+    }
+
+    /// the held transaction if it belongs to `principal`, unknown and foreign ids look the same
+    fn get(
+        &self,
+        principal: &Principal,
+        id: &str,
+    ) -> Result<Arc<tokio::sync::Mutex<HeldTx>>, Error> {
+        match self.held.lock().unwrap().get(id) {
+            Some(entry) if entry.username == principal.username => Ok(entry.tx.clone()),
+            _ => Err(Error::UnknownTransaction(id.to_string())),
+        }
+    }
+
+    fn remove(&self, id: &str) {
+        self.held.lock().unwrap().remove(id);
+    }
+
+    /// roll back and release transactions nobody touched for `idle_timeout`
+    pub fn spawn_reaper(transactions: web::Data<Transactions>) {
+        tokio::spawn(async move {
+            let period = (transactions.idle_timeout / 4).max(Duration::from_secs(1));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let mut expired = vec![];
+                transactions.held.lock().unwrap().retain(|id, entry| {
+                    //a locked transaction is busy running statements, not idle
+                    let Ok(mut tx) = entry.tx.try_lock() else {
+                        return true;
+                    };
+                    if tx.last_used.elapsed() < transactions.idle_timeout {
+                        return true;
+                    }
+                    if let Some(conn) = tx.conn.take() {
+                        println!(
+                            "rolling back idle transaction {} of '{}' on db '{}'",
+                            id, entry.username, tx.db
+                        );
+                        expired.push(conn);
+                    }
+                    false
+                });
+                for conn in expired {
+                    let _ = finish(conn, "ROLLBACK").await;
+                }
+            }
+        });
+    }
+}
+
+/// start a transaction, returns `{ "id": "..." }`
+#[post("/tx")]
+pub async fn begin(
+    pools: web::Data<Pools>,
+    transactions: web::Data<Transactions>,
+    req: actix_web::HttpRequest,
+    principal: web::ReqData<Principal>,
+) -> impl Responder {
+    if let Err(err) = principal.may_send_sql() {
+        return err.error_response();
+    }
+    let selected = match select(&req, &pools) {
+        Err(err) => return err.error_response(),
+        Ok(selected) => selected,
+    };
+    let start = match principal.read_only {
+        true => "START TRANSACTION READ ONLY",
+        false => "START TRANSACTION",
+    };
+    //rolled back by `finish()` rather than `Acquired::release()`
+    let Acquired { conn, watchdog, .. } = match selected.begin(start).await {
+        Err(err) => return err.error_response(),
+        Ok(acquired) => acquired,
+    };
+    let tx = HeldTx {
+        conn: Some(conn),
+        db: db_name(&req).unwrap_or("db").to_string(),
+        read_only: principal.read_only,
+        limits: selected.limits,
+        watchdog,
+        last_used: Instant::now(),
+    };
+    let id = transactions.insert(principal.username.clone(), tx);
+    HttpResponse::Ok().json(serde_json::json!({ "id": id }))
+}
+
+/// run an array of query json strings inside the transaction
+///
+/// a failing statement does not end the transaction, the client decides whether to commit or roll back.
+/// except when mysql already rolled it back (deadlock, lost connection), then the rest of it is rolled back
+/// and forgotten and the response has `"aborted": true`. statements that would commit it implicitly
+/// (DDL, COMMIT, ...) are refused with a 400 and not run
+#[post("/tx/{id}")]
+pub async fn statements(
+    transactions: web::Data<Transactions>,
+    principal: web::ReqData<Principal>,
+    id: web::Path<String>,
+    queries: web::Json<Vec<String>>,
+) -> impl Responder {
+    let tx = match transactions.get(&principal, &id) {
+        Err(err) => return err.error_response(),
+        Ok(tx) => tx,
+    };
+    let mut tx = tx.lock().await;
+    let HeldTx {
+        conn: held,
+        db,
+        read_only,
+        limits,
+        watchdog,
+        last_used,
+    } = &mut *tx;
+    let Some(conn) = held else {
+        let err = Error::UnknownTransaction(id.to_string());
+        return err.error_response();
+    };
+    if !principal.may_use(db) {
+        let err = Error::Forbidden(principal.username.clone(), db.clone());
+        return err.error_response();
+    }
+    watchdog.restart();
+    if *read_only && !queries.iter().all(|q| sqlx_mysql_json::is_select(q)) {
+        let err = Error::ReadOnly(principal.username.clone());
+        return err.error_response();
+    }
+
+    let mut results: Vec<serde_json::Value> = vec![];
+    let mut failure = None;
+    let mut aborted = false;
+    for (index, q) in queries.iter().enumerate() {
+        if sqlx_mysql_json::ends_transaction(q) {
+            failure = Some((index, Error::EndsTransaction));
+            break;
+        }
+        let query = sqlx_mysql_json::query_limited(&mut **conn, q, *limits);
+        match watchdog.run(query).await {
+            Ok(Ok(result)) => results.push(result),
+            Ok(Err(err)) => {
+                //mysql rolls back the whole transaction on a deadlock and the connection
+                //is back in autocommit mode, later statements would commit one by one.
+                //a lock wait timeout (1205) only rolls back the statement
+                aborted = err.number() == Some(1213) || err.class() == Class::Unavailable;
+                failure = Some((index, Error::from(err)));
+                break;
+            }
+            Err(err) => {
+                aborted = watchdog.abandoned();
+                failure = Some((index, err));
+                break;
+            }
+        }
+    }
+    *last_used = Instant::now();
+
+    let Some((index, err)) = failure else {
+        return HttpResponse::Ok().json(results);
+    };
+    if aborted {
+        let conn = held.take();
+        let abandoned = watchdog.abandoned();
+        drop(tx);
+        transactions.remove(&id);
+        match conn {
+            //closing the connection makes mysql roll back
+            Some(conn) if abandoned => drop(conn.detach()),
+            Some(conn) => {
+                let _ = finish(conn, "ROLLBACK").await;
+            }
+            None => {}
+        }
+    }
+    HttpResponse::build(err.status_code()).json(serde_json::json!({
+        "error": err.to_value(),
+        "index": index,
+        "results": results,
+        "aborted": aborted,
+    }))
+}
+
+#[post("/tx/{id}/commit")]
+pub async fn commit(
+    transactions: web::Data<Transactions>,
+    principal: web::ReqData<Principal>,
+    id: web::Path<String>,
+) -> impl Responder {
+    end(&transactions, &principal, &id, "COMMIT").await
+}
+
+#[post("/tx/{id}/rollback")]
+pub async fn rollback(
+    transactions: web::Data<Transactions>,
+    principal: web::ReqData<Principal>,
+    id: web::Path<String>,
+) -> impl Responder {
+    end(&transactions, &principal, &id, "ROLLBACK").await
+}
+
+async fn end(
+    transactions: &Transactions,
+    principal: &Principal,
+    id: &str,
+    sql: &str,
+) -> HttpResponse {
+    let tx = match transactions.get(principal, id) {
+        Err(err) => return err.error_response(),
+        Ok(tx) => tx,
+    };
+    //waits for statements still running on it
+    let mut tx = tx.lock().await;
+    if !principal.may_use(&tx.db) {
+        let err = Error::Forbidden(principal.username.clone(), tx.db.clone());
+        return err.error_response();
+    }
+    let conn = tx.conn.take();
+    drop(tx);
+    transactions.remove(id);
+    let Some(conn) = conn else {
+        let err = Error::UnknownTransaction(id.to_string());
+        return err.error_response();
+    };
+    match finish(conn, sql).await {
+        Ok(_) => HttpResponse::Ok().json(sql),
+        Err(err) => Error::Transaction(sql.to_string(), err.into()).error_response(),
+    }
+}